@@ -11,7 +11,7 @@ use structopt::StructOpt;
 )]
 pub struct Args {
     /// Extra config files to read from.
-    #[structopt(name = "config", long = "config")]
+    #[structopt(name = "config", long = "config", number_of_values = 1)]
     configs: Vec<PathBuf>,
 
     /// When this flag is passed, the program sleeps continuously in the background and
@@ -20,8 +20,69 @@ pub struct Args {
     /// * SIGCONT | SIGUSR1 -- listen and run a single command.
     ///
     /// * SIGHUP -- reload the assistant's configuration from the config files.
+    ///
+    /// * SIGTERM | SIGINT -- shut down gracefully.
+    ///
+    /// It can also be controlled through the socket passed to `--socket`.
     #[structopt(name = "daemonize", long = "daemonize", short = "d")]
     daemonize: bool,
+
+    /// When daemonized, also accept line-delimited JSON requests on a Unix domain socket at this path.
+    #[structopt(name = "socket", long = "socket")]
+    socket: Option<PathBuf>,
+
+    /// Read audio from a mono, 16-bit PCM WAV file instead of the microphone.
+    /// The file's sample rate must match the model's.
+    #[structopt(name = "input-file", long = "input-file")]
+    input_file: Option<PathBuf>,
+
+    /// Print every audio host and input device, along with their supported configurations, and exit.
+    #[structopt(name = "list-devices", long = "list-devices")]
+    list_devices: bool,
+
+    /// Run the command matching this text as if it had been spoken, without loading the model or
+    /// opening the microphone.
+    #[structopt(
+        name = "text",
+        long = "text",
+        conflicts_with_all = &["daemonize", "input-file", "stdin"]
+    )]
+    text: Option<String>,
+
+    /// Like `--text`, but read each utterance as a line from standard input. Each line after the
+    /// first is heard in the mode the previous one switched to.
+    #[structopt(
+        name = "stdin",
+        long = "stdin",
+        conflicts_with_all = &["daemonize", "input-file"]
+    )]
+    stdin: bool,
+
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+/// Tools for working on the config, run instead of the assistant itself.
+#[derive(Debug, StructOpt)]
+pub enum Subcommand {
+    /// Show how some text would be matched against the commands, step by step, without running
+    /// anything or loading the model.
+    Match {
+        /// The text to match, as if it had been spoken.
+        text: String,
+
+        /// Start matching in this mode instead of the root mode.
+        #[structopt(long = "mode")]
+        mode: Option<String>,
+
+        /// Print the explanation as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Read every config file, reporting all of the problems with them along with the file each
+    /// came from, and exit with an error if there are any.
+    CheckConfig,
 }
 
 impl Args {
@@ -50,6 +111,30 @@ impl Args {
     pub fn daemonize(&self) -> bool {
         self.daemonize
     }
+
+    pub fn socket(&self) -> Option<&Path> {
+        self.socket.as_ref().map(|p| p.as_ref())
+    }
+
+    pub fn list_devices(&self) -> bool {
+        self.list_devices
+    }
+
+    pub fn input_file(&self) -> Option<&Path> {
+        self.input_file.as_ref().map(|p| p.as_ref())
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_ref().map(|s| s.as_ref())
+    }
+
+    pub fn stdin(&self) -> bool {
+        self.stdin
+    }
+
+    pub fn subcommand(&self) -> Option<&Subcommand> {
+        self.subcommand.as_ref()
+    }
 }
 
 struct FilteredPathIter<T: AsRef<Path>, I: Iterator<Item = T>, H: BuildHasher = RandomState> {
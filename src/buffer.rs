@@ -1,11 +1,21 @@
+use crate::convert::FormatConverter;
 use crate::error::{AssistantRsError, CpalError};
+use crate::recognizer::RecognizerStream;
 use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::SampleFormat;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::time::Duration;
 
+/// Converts a number of samples into the length of audio they represent.
+pub fn samples_to_duration(samples: usize, sample_rate: u32) -> Duration {
+    let nanos = ((1_000_000_000u64) * (samples as u64)) / (sample_rate as u64);
+    Duration::from_nanos(nanos)
+}
+
 /// A buffer used to store speech data and monitor the beginning and end of speech.
 pub struct SpeechLoader {
-    stream: deepspeech::dynamic::Stream,
+    stream: Box<dyn RecognizerStream>,
     sample_rate: u32,
     total_samples: usize,
     current_text: String,
@@ -16,7 +26,7 @@ pub struct SpeechLoader {
 type DidChange = bool;
 
 impl SpeechLoader {
-    pub fn new(stream: deepspeech::dynamic::Stream, sample_rate: u32) -> Self {
+    pub fn new(stream: Box<dyn RecognizerStream>, sample_rate: u32) -> Self {
         Self {
             stream,
             sample_rate,
@@ -29,13 +39,16 @@ impl SpeechLoader {
 
     /// The time since the loader detected new speech.
     pub fn time_since_change(&self) -> Duration {
-        let nanos =
-            ((1_000_000_000u64) * (self.samples_since_change as u64)) / (self.sample_rate as u64);
-        Duration::from_nanos(nanos)
+        samples_to_duration(self.samples_since_change, self.sample_rate)
+    }
+
+    /// The total length of the audio `push`ed to this loader.
+    pub fn time_listened(&self) -> Duration {
+        samples_to_duration(self.total_samples, self.sample_rate)
     }
 
     /// Pushes new audio sample data to the model; returns whether or not the samples contained new speech information on success.
-    pub fn push(&mut self, data: &[i16]) -> Result<DidChange, deepspeech::errors::DeepspeechError> {
+    pub fn push(&mut self, data: &[i16]) -> Result<DidChange, AssistantRsError> {
         self.stream.feed_audio(data);
         self.total_samples += data.len();
         let mut next_text = self.stream.intermediate_decode()?;
@@ -54,6 +67,15 @@ impl SpeechLoader {
         }
     }
 
+    /// Pushes new audio sample data to the model without decoding it.
+    ///
+    /// Since nothing is decoded, `current_text` and `time_since_change` are not updated.
+    pub fn feed(&mut self, data: &[i16]) {
+        self.stream.feed_audio(data);
+        self.total_samples += data.len();
+        self.raw_data.extend_from_slice(data);
+    }
+
     /// Gets the current transcript of the audio stored in this loader.
     pub fn current_text(&self) -> &str {
         &self.current_text
@@ -65,9 +87,10 @@ impl SpeechLoader {
         self.total_samples
     }
 
-    /// "Finishes" the loader, returning the text transcription and raw audio data.
-    pub fn finish(self) -> (String, Vec<i16>) {
-        (self.current_text, self.raw_data)
+    /// "Finishes" the loader, returning the final text transcription and raw audio data.
+    pub fn finish(self) -> Result<(String, Vec<i16>), AssistantRsError> {
+        let final_text = self.stream.finish()?;
+        Ok((final_text, self.raw_data))
     }
 }
 
@@ -130,6 +153,12 @@ impl<T: Clone> WaitableBuffer<T> {
         retvl.append(&mut lock);
         Ok(retvl)
     }
+
+    /// Removes all data from the buffer.
+    pub fn clear(&self) {
+        let mut lock = self.data.lock().unwrap_or_else(|e| e.into_inner());
+        lock.clear();
+    }
 }
 
 /// The error returned when `WaitableBuffer::wait_until_timeout` times out.
@@ -137,6 +166,45 @@ impl<T: Clone> WaitableBuffer<T> {
 #[error("Timed out.")]
 pub struct Timeout {}
 
+/// A source of mono, 16-bit audio at the model's sample rate.
+pub trait AudioSource {
+    /// Waits until at least `target` samples are available before returning them.
+    /// All available data is returned, not just the number of samples specified by `target`.
+    ///
+    /// An empty return value indicates that the source has run out of audio.
+    fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError>;
+
+    /// Throws away any audio that has already been recorded but not yet returned.
+    ///
+    /// Sources that are not recorded live have nothing to throw away.
+    fn discard_buffered(&mut self) {}
+}
+
+/// Wraps another `AudioSource`, failing with `AssistantRsError::Cancelled` once `cancel` is set.
+pub struct CancellableSource<'a> {
+    inner: &'a mut dyn AudioSource,
+    cancel: &'a AtomicBool,
+}
+
+impl<'a> CancellableSource<'a> {
+    pub fn new(inner: &'a mut dyn AudioSource, cancel: &'a AtomicBool) -> Self {
+        Self { inner, cancel }
+    }
+}
+
+impl<'a> AudioSource for CancellableSource<'a> {
+    fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(AssistantRsError::Cancelled);
+        }
+        self.inner.wait_until(target)
+    }
+
+    fn discard_buffered(&mut self) {
+        self.inner.discard_buffered()
+    }
+}
+
 /// Manages recieving audio from the microphone.
 pub struct AudioReciever {
     buffer: Arc<WaitableBuffer<i16>>,
@@ -154,21 +222,27 @@ impl Drop for AudioReciever {
 
 impl AudioReciever {
     /// Builts a new `AudioReciever`, including building the internal buffers and starting the `cpal` input stream.
+    ///
+    /// The device records using `config`, and its audio is converted to mono, 16-bit samples at `sample_rate`.
     pub fn construct(
         device: &cpal::Device,
-        config: &cpal::StreamConfig,
+        config: &cpal::SupportedStreamConfig,
+        sample_rate: u32,
     ) -> Result<Self, AssistantRsError> {
         let buffer = Arc::new(WaitableBuffer::<i16>::new());
-        let handle = Arc::clone(&buffer);
         let (error_send, error_recv) = crossbeam::bounded::<AssistantRsError>(1);
 
-        let stream = device
-            .build_input_stream(
-                &config,
-                move |dt: &[i16], _cb| handle.push_slice(dt),
-                move |e| error_send.send(CpalError::from(e).into()).unwrap(),
-            )
-            .map_err(|e| CpalError::from(e))?;
+        let stream = match config.sample_format() {
+            SampleFormat::I16 => {
+                build_input_stream::<i16>(device, config, sample_rate, &buffer, error_send)
+            }
+            SampleFormat::U16 => {
+                build_input_stream::<u16>(device, config, sample_rate, &buffer, error_send)
+            }
+            SampleFormat::F32 => {
+                build_input_stream::<f32>(device, config, sample_rate, &buffer, error_send)
+            }
+        }?;
         stream.play().map_err(|e| CpalError::from(e))?;
         let retvl = Self {
             buffer,
@@ -196,3 +270,66 @@ impl AudioReciever {
         }
     }
 }
+
+/// Builds an input stream that records samples of type `T` and converts them into `buffer`.
+fn build_input_stream<T: cpal::Sample>(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    sample_rate: u32,
+    buffer: &Arc<WaitableBuffer<i16>>,
+    error_send: crossbeam::Sender<AssistantRsError>,
+) -> Result<cpal::Stream, AssistantRsError> {
+    let handle = Arc::clone(buffer);
+    let mut converter =
+        FormatConverter::new(config.channels(), config.sample_rate().0, sample_rate);
+    let stream = device
+        .build_input_stream(
+            &config.config(),
+            move |dt: &[T], _cb| handle.push_slice(&converter.convert(dt)),
+            move |e| {
+                // Only the first error is reported; the rest would just be noise.
+                let _ = error_send.try_send(CpalError::from(e).into());
+            },
+        )
+        .map_err(CpalError::from)?;
+    Ok(stream)
+}
+
+impl AudioSource for AudioReciever {
+    fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError> {
+        AudioReciever::wait_until(self, target)
+    }
+
+    fn discard_buffered(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::SpeechRecognizer;
+    use crate::testing::ScriptedRecognizer;
+
+    #[test]
+    fn test_loader_time_since_change() {
+        let mut recognizer = ScriptedRecognizer::new(16000).with_utterance(1600, "hello");
+        let mut loader = SpeechLoader::new(recognizer.create_stream().unwrap(), 16000);
+        let chunk = vec![0i16; 800];
+
+        assert!(!loader.push(&chunk).unwrap());
+        assert_eq!(loader.current_text(), "");
+        assert!(loader.push(&chunk).unwrap());
+        assert_eq!(loader.current_text(), "hello");
+        assert_eq!(loader.time_since_change(), Duration::from_millis(0));
+        assert!(!loader.push(&chunk).unwrap());
+        assert!(!loader.push(&chunk).unwrap());
+        assert_eq!(loader.time_since_change(), Duration::from_millis(100));
+        assert_eq!(loader.time_listened(), Duration::from_millis(200));
+        assert_eq!(loader.num_samples(), 3200);
+
+        let (text, raw) = loader.finish().unwrap();
+        assert_eq!(text, "hello");
+        assert_eq!(raw.len(), 2400);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::config::Config;
+use crate::error::{error_message, ConfigError};
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A problem found while checking the config, along with the files it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// The files the problem was found in, or none if it is about the config as a whole.
+    pub sources: Vec<PathBuf>,
+
+    pub message: String,
+}
+
+impl Problem {
+    fn new(sources: Vec<PathBuf>, err: &ConfigError) -> Self {
+        Self {
+            sources,
+            message: error_message(err),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|pt| pt.display().to_string())
+            .collect();
+        if sources.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", sources.join(", "), self.message)
+        }
+    }
+}
+
+/// The result of checking a series of config files.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigCheck {
+    /// The files that were read, in order.
+    pub read: Vec<PathBuf>,
+
+    /// The files that were skipped because they do not exist.
+    pub skipped: Vec<PathBuf>,
+
+    /// Problems that stop the config from being used.
+    pub errors: Vec<Problem>,
+
+    /// Things that work, but probably not as intended.
+    pub warnings: Vec<Problem>,
+}
+
+/// Reads and combines the config files at `paths` like `config::cascade_configs`, reporting
+/// every problem found along the way instead of stopping at the first.
+///
+/// A file that cannot be read, or that conflicts with the files before it, is left out of the
+/// combined config, so problems with the rest can still be found.
+pub fn check_configs(paths: &[impl AsRef<Path>]) -> ConfigCheck {
+    let mut retvl = ConfigCheck::default();
+    let mut configs = Vec::new();
+    for pt in paths {
+        let pt = pt.as_ref();
+        if !pt.is_file() {
+            retvl.skipped.push(pt.to_owned());
+            continue;
+        }
+        retvl.read.push(pt.to_owned());
+        match Config::read_file(pt) {
+            Ok(conf) => configs.push((pt.to_owned(), conf)),
+            Err(e) => retvl.errors.push(Problem::new(vec![pt.to_owned()], &e)),
+        }
+    }
+
+    let mut merged = Config::default();
+    for (pt, conf) in configs.iter() {
+        match merged.clone().or_else(conf.clone()) {
+            Ok(next) => merged = next,
+            Err(e) => retvl.errors.push(Problem::new(vec![pt.clone()], &e)),
+        }
+    }
+    let sources_of = |err: &ConfigError| {
+        configs
+            .iter()
+            .filter(|(_, conf)| conf.modes.is_source_of(err))
+            .map(|(pt, _)| pt.clone())
+            .collect()
+    };
+    if let Err(e) = merged.modes.load_pronunciations() {
+        retvl.errors.push(Problem::new(sources_of(&e), &e));
+    }
+    for err in merged.problems() {
+        retvl.errors.push(Problem::new(sources_of(&err), &err));
+    }
+
+    // Unless the config insists on them, words without a pronounciation only get a warning.
+    if !merged.modes.require_pronunciations() {
+        for err in merged.modes.unknown_words() {
+            retvl.warnings.push(Problem::new(sources_of(&err), &err));
+        }
+    }
+    retvl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let retvl = temp_path(name);
+        std::fs::write(&retvl, contents).unwrap();
+        retvl
+    }
+
+    #[test]
+    fn test_check_configs() {
+        let base = write_temp(
+            "check-base.toml",
+            r#"
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "terminal"
+            command = ""
+
+            [[mode]]
+            name = "unused"
+
+            [[mode.command]]
+            message = "fire fox"
+            command = "firefox"
+            "#,
+        );
+        let extra = write_temp(
+            "check-extra.toml",
+            r#"
+            [[command]]
+            message = "open zorblax"
+            mode = "firefox"
+            "#,
+        );
+        let broken = write_temp("check-broken.toml", "this is not toml");
+        let missing = temp_path("check-missing.toml");
+
+        let check = check_configs(&[&base, &missing, &broken, &extra]);
+        assert_eq!(
+            check.read,
+            vec![base.clone(), broken.clone(), extra.clone()]
+        );
+        assert_eq!(check.skipped, vec![missing]);
+
+        // Every problem is found, not just the first.
+        let errors: Vec<_> = check.errors.iter().map(|p| p.to_string()).collect();
+        let parse_error = format!("{}: error parsing config file: ", broken.display());
+        assert!(errors[0].starts_with(&parse_error));
+        assert_eq!(
+            errors[1..],
+            [
+                "no model path passed".to_owned(),
+                format!(
+                    "{}: the command for message terminal is empty",
+                    base.display()
+                ),
+                format!(
+                    "{}, {}: mode firefox is referenced but does not exist",
+                    base.display(),
+                    extra.display()
+                ),
+                format!(
+                    "{}: mode unused was defined, but cannot be reached in the mode tree",
+                    base.display()
+                ),
+            ]
+        );
+
+        assert_eq!(check.warnings.len(), 1);
+        assert_eq!(check.warnings[0].sources, vec![extra.clone()]);
+        assert!(check.warnings[0].message.contains("zorblax"));
+
+        for pt in [base, extra, broken].iter() {
+            std::fs::remove_file(pt).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_check_required_pronunciations() {
+        let base = write_temp(
+            "pronunciations-base.toml",
+            r#"
+            model-path = "model.pbmm"
+            require-pronunciations = true
+
+            [[command]]
+            message = "open blorptop"
+            command = "blorptop"
+
+            [[command]]
+            message = "open zorblax"
+            command = "zorblax"
+            "#,
+        );
+        let words = write_temp(
+            "pronunciations-words.toml",
+            r#"
+            [pronunciations]
+            blorptop = "B L AO1 R P T AA2 P"
+            "#,
+        );
+
+        // Words from any file count, and the rest are errors rather than warnings.
+        let check = check_configs(&[&base, &words]);
+        assert!(check.warnings.is_empty());
+        let errors: Vec<_> = check.errors.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            errors,
+            [format!(
+                "{}: no pronunciation is known for zorblax in message open zorblax",
+                base.display()
+            )]
+        );
+
+        std::fs::write(&words, "[pronunciations]\nblorptop = \"BLORP\"").unwrap();
+        let check = check_configs(&[&base, &words]);
+        assert_eq!(check.errors[0].sources, vec![words.clone()]);
+        assert!(check.errors[0].message.starts_with("invalid pronunciation"));
+
+        for pt in [base, words].iter() {
+            std::fs::remove_file(pt).unwrap();
+        }
+    }
+}
@@ -2,8 +2,10 @@ use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::error::ConfigError;
+use crate::exec::CommandLine;
 use crate::modes::ModeTree;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
@@ -83,11 +85,264 @@ impl DeepspeechConfig {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Default)]
+/// Controls how long the assistant listens for a single utterance.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListenConfig {
+    pub end_silence_ms: Option<u64>,
+    pub max_utterance_ms: Option<u64>,
+    pub start_timeout_ms: Option<u64>,
+    pub vad: Option<bool>,
+    pub vad_threshold_db: Option<u32>,
+    pub vad_preroll_ms: Option<u64>,
+}
+
+impl ListenConfig {
+    /// Combines the information in `self` with `other`.
+    ///
+    /// If a field is defined in both `self` and `other`, the value in `self` is used.
+    pub fn or_else(mut self, other: ListenConfig) -> Self {
+        if self.end_silence_ms.is_none() {
+            self.end_silence_ms = other.end_silence_ms;
+        }
+        if self.max_utterance_ms.is_none() {
+            self.max_utterance_ms = other.max_utterance_ms;
+        }
+        if self.start_timeout_ms.is_none() {
+            self.start_timeout_ms = other.start_timeout_ms;
+        }
+        if self.vad.is_none() {
+            self.vad = other.vad;
+        }
+        if self.vad_threshold_db.is_none() {
+            self.vad_threshold_db = other.vad_threshold_db;
+        }
+        if self.vad_preroll_ms.is_none() {
+            self.vad_preroll_ms = other.vad_preroll_ms;
+        }
+        self
+    }
+
+    /// Returns how long the speaker must be quiet before the speech is considered finished.
+    ///
+    /// Without voice activity detection, this is how long the transcript must stay the same,
+    /// and defaults to 100 milliseconds. With it, this is how long since the last voiced audio,
+    /// and defaults to 600 milliseconds to allow for pauses between words.
+    pub fn end_silence(&self) -> Duration {
+        let default = if self.vad() { 600 } else { 100 };
+        Duration::from_millis(self.end_silence_ms.unwrap_or(default))
+    }
+
+    /// Returns whether to detect speech from the audio's energy rather than from the transcript.
+    ///
+    /// Defaults to `false`.
+    pub fn vad(&self) -> bool {
+        self.vad.unwrap_or(false)
+    }
+
+    /// Returns how many decibels above the background noise audio must be to count as speech.
+    ///
+    /// Defaults to 10.
+    pub fn vad_threshold_db(&self) -> u32 {
+        self.vad_threshold_db.unwrap_or(10)
+    }
+
+    /// Returns how much audio from before speech is detected to pass to the recognizer.
+    ///
+    /// Defaults to 300 milliseconds.
+    pub fn vad_preroll(&self) -> Duration {
+        Duration::from_millis(self.vad_preroll_ms.unwrap_or(300))
+    }
+
+    /// Returns the longest the assistant will listen to a single utterance, if it has been set.
+    pub fn max_utterance(&self) -> Option<Duration> {
+        self.max_utterance_ms.map(Duration::from_millis)
+    }
+
+    /// Returns how long the assistant will wait for speech to start, if it has been set.
+    pub fn start_timeout(&self) -> Option<Duration> {
+        self.start_timeout_ms.map(Duration::from_millis)
+    }
+}
+
+/// Controls which audio devices the assistant uses.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AudioConfig {
+    /// The name, or part of the name, of the audio host to find devices on.
+    pub audio_host: Option<String>,
+
+    /// The name, or part of the name, of the microphone to listen with.
+    pub audio_device: Option<String>,
+}
+
+impl AudioConfig {
+    /// Combines the information in `self` with `other`.
+    ///
+    /// If a field is defined in both `self` and `other`, the value in `self` is used.
+    pub fn or_else(mut self, other: AudioConfig) -> Self {
+        if self.audio_host.is_none() {
+            self.audio_host = other.audio_host;
+        }
+        if self.audio_device.is_none() {
+            self.audio_device = other.audio_device;
+        }
+        self
+    }
+}
+
+/// Controls the sounds the assistant plays to show what it is doing.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct CueConfig {
+    /// Whether to play cues at all.
+    pub feedback_cues: Option<bool>,
+
+    /// The name, or part of the name, of the speaker to play cues on.
+    pub cue_device: Option<String>,
+
+    pub listening_cue: Option<CueSound>,
+    pub matched_cue: Option<CueSound>,
+    pub no_match_cue: Option<CueSound>,
+    pub mode_cue: Option<CueSound>,
+}
+
+impl CueConfig {
+    /// Combines the information in `self` with `other`.
+    ///
+    /// If a field is defined in both `self` and `other`, the value in `self` is used.
+    pub fn or_else(mut self, other: CueConfig) -> Self {
+        if self.feedback_cues.is_none() {
+            self.feedback_cues = other.feedback_cues;
+        }
+        if self.cue_device.is_none() {
+            self.cue_device = other.cue_device;
+        }
+        if self.listening_cue.is_none() {
+            self.listening_cue = other.listening_cue;
+        }
+        if self.matched_cue.is_none() {
+            self.matched_cue = other.matched_cue;
+        }
+        if self.no_match_cue.is_none() {
+            self.no_match_cue = other.no_match_cue;
+        }
+        if self.mode_cue.is_none() {
+            self.mode_cue = other.mode_cue;
+        }
+        self
+    }
+
+    /// Returns whether to play cues.
+    ///
+    /// Defaults to `false`.
+    pub fn feedback_cues(&self) -> bool {
+        self.feedback_cues.unwrap_or(false)
+    }
+
+    /// Returns the sound to play when the assistant starts listening.
+    ///
+    /// Defaults to a short, high beep.
+    pub fn listening_cue(&self) -> CueSound {
+        self.listening_cue.clone().unwrap_or(CueSound::Tone {
+            tone_hz: 880,
+            duration_ms: 120,
+        })
+    }
+
+    /// Returns the sound to play when an utterance matches a command.
+    ///
+    /// Defaults to a very short, higher beep.
+    pub fn matched_cue(&self) -> CueSound {
+        self.matched_cue.clone().unwrap_or(CueSound::Tone {
+            tone_hz: 1320,
+            duration_ms: 80,
+        })
+    }
+
+    /// Returns the sound to play when an utterance does not match any command.
+    ///
+    /// Defaults to a low buzz.
+    pub fn no_match_cue(&self) -> CueSound {
+        self.no_match_cue.clone().unwrap_or(CueSound::Tone {
+            tone_hz: 220,
+            duration_ms: 250,
+        })
+    }
+
+    /// Returns the sound to play when the assistant switches into a mode.
+    ///
+    /// Defaults to a short beep between the listening and matched beeps.
+    pub fn mode_cue(&self) -> CueSound {
+        self.mode_cue.clone().unwrap_or(CueSound::Tone {
+            tone_hz: 660,
+            duration_ms: 100,
+        })
+    }
+}
+
+/// A sound to play as a cue.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CueSound {
+    /// A mono, 16-bit WAV file, like `{ file = "/usr/share/sounds/beep.wav" }`.
+    File { file: PathBuf },
+
+    /// A sine wave, like `{ tone-hz = 880, duration-ms = 120 }`.
+    #[serde(rename_all = "kebab-case")]
+    Tone { tone_hz: u32, duration_ms: u64 },
+}
+
+/// Controls how the assistant speaks.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TtsConfig {
+    /// The command that speaks the text written to its standard input, like `espeak -`.
+    pub tts_command: Option<CommandLine>,
+
+    /// How long the `tts-command` may take to speak before it is killed.
+    pub tts_timeout_ms: Option<u64>,
+}
+
+impl TtsConfig {
+    /// Combines the information in `self` with `other`.
+    ///
+    /// If a field is defined in both `self` and `other`, the value in `self` is used.
+    pub fn or_else(mut self, other: TtsConfig) -> Self {
+        if self.tts_command.is_none() {
+            self.tts_command = other.tts_command;
+        }
+        if self.tts_timeout_ms.is_none() {
+            self.tts_timeout_ms = other.tts_timeout_ms;
+        }
+        self
+    }
+
+    /// Returns how long the `tts-command` may take to speak before it is killed.
+    ///
+    /// Defaults to 30 seconds, so that a command that hangs cannot stall the assistant.
+    pub fn tts_timeout(&self) -> Duration {
+        Duration::from_millis(self.tts_timeout_ms.unwrap_or(30_000))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct Config {
     #[serde(flatten)]
     pub deepspeech_config: DeepspeechConfig,
 
+    #[serde(flatten)]
+    pub listen_config: ListenConfig,
+
+    #[serde(flatten)]
+    pub audio_config: AudioConfig,
+
+    #[serde(flatten)]
+    pub cue_config: CueConfig,
+
+    #[serde(flatten)]
+    pub tts_config: TtsConfig,
+
     #[serde(flatten)]
     pub modes: ModeTree,
 }
@@ -109,12 +364,26 @@ impl Config {
         Ok(())
     }
 
+    /// Finds every problem with the config, not just the first one that `verify` reports.
+    pub fn problems(&self) -> Vec<ConfigError> {
+        let mut retvl = Vec::new();
+        if let Err(e) = self.deepspeech_config.verify() {
+            retvl.push(e);
+        }
+        retvl.extend(self.modes.problems());
+        retvl
+    }
+
     /// Combines the information in `self` with `other`.
     ///
     /// If a field is defined in both `self` and `other`, the value in `self` is used.
     /// If two commands share the same message, the one in `self` is used.
     pub fn or_else(mut self, other: Config) -> Result<Self, ConfigError> {
         self.deepspeech_config = self.deepspeech_config.or_else(other.deepspeech_config);
+        self.listen_config = self.listen_config.or_else(other.listen_config);
+        self.audio_config = self.audio_config.or_else(other.audio_config);
+        self.cue_config = self.cue_config.or_else(other.cue_config);
+        self.tts_config = self.tts_config.or_else(other.tts_config);
         self.modes = self.modes.or_else(other.modes)?;
         Ok(self)
     }
@@ -123,7 +392,8 @@ impl Config {
 /// Combines the configuration information in a series of files into a single `Config`.
 ///
 /// If a file does not exist, it is silently skipped. Files are read in order, so if two
-/// files specify the same field the earlier value will be used.
+/// files specify the same field the earlier value will be used. The pronounciations from
+/// every file are used for the messages in all of them.
 pub fn cascade_configs(paths: &[impl AsRef<Path>]) -> Result<Config, ConfigError> {
     let mut config = Config::default();
     for pt in paths {
@@ -133,6 +403,7 @@ pub fn cascade_configs(paths: &[impl AsRef<Path>]) -> Result<Config, ConfigError
         let pt_conf = Config::read_file(pt)?;
         config = config.or_else(pt_conf)?;
     }
+    config.modes.load_pronunciations()?;
     Ok(config)
 }
 
@@ -1,204 +1,1018 @@
-use crate::buffer::{AudioReciever, SpeechLoader};
+use crate::buffer::{self, AudioSource, CancellableSource, SpeechLoader};
 use crate::config;
-use crate::config::{Config, DeepspeechConfig};
-use crate::error::{AssistantRsError, CpalError};
+use crate::config::{AudioConfig, Config, ListenConfig};
+use crate::devices;
+use crate::error::{error_message, AssistantRsError, ConfigError};
+use crate::exec::{self, CommandLine, CommandStatus, ExecPolicy};
+use crate::feedback::{CpalCuePlayer, Cue, CuePlayer, NullCuePlayer};
 use crate::metrics;
-use crate::modes::Command;
-use crate::utils::CpalDeviceUtils;
-use cpal::traits::HostTrait;
-use cpal::BuildStreamError;
-use deepspeech::dynamic::Model;
+use crate::modes::{Action, Command, OnTimeout};
+use crate::recognizer::{DeepspeechRecognizer, SpeechRecognizer};
+use crate::slots::{self, MessagePattern, SlotValues, SpokenWords};
+use crate::tts::{self, NullTtsSink, TtsSink};
+use crate::vad::VoiceDetector;
+use crate::wav::WavFileSource;
 
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::PathBuf;
-use std::process;
-use std::time::Duration;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Where the assistant reads its audio from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AudioInput {
+    /// Listen to the microphone.
+    Microphone,
+
+    /// Stream pre-recorded audio from a WAV file.
+    WavFile(PathBuf),
+}
+
+impl AudioInput {
+    /// Opens the audio source, which must produce audio at `sample_rate`.
+    fn open(
+        &self,
+        sample_rate: u32,
+        conf: &AudioConfig,
+    ) -> Result<Box<dyn AudioSource>, AssistantRsError> {
+        match self {
+            AudioInput::Microphone => Ok(Box::new(devices::build_audio_stream(sample_rate, conf)?)),
+            AudioInput::WavFile(path) => Ok(Box::new(WavFileSource::open(path, sample_rate)?)),
+        }
+    }
+}
+
+/// What happened during a single run of the assistant.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunReport {
+    /// The transcript of each utterance heard during the run, in order.
+    pub transcripts: Vec<String>,
+
+    /// The terminal commands that were run, in order.
+    pub commands: Vec<String>,
+
+    /// How each of the terminal commands ended, in the same order as `commands`.
+    pub command_statuses: Vec<CommandStatus>,
+
+    /// The mode the last utterance was heard in, or `None` for the root mode.
+    pub final_mode: Option<String>,
+
+    /// Whether the last utterance matched a command.
+    pub matched: bool,
+
+    /// The values captured by slots in the matched messages.
+    pub slots: SlotValues,
+
+    /// The built-in actions that were carried out, in order.
+    pub actions: Vec<Action>,
+
+    /// Whether nobody spoke within a mode's `timeout-ms`, ending the run or returning to the root mode.
+    pub timed_out: bool,
+
+    /// What the assistant said, in order.
+    pub spoken: Vec<String>,
+}
 
 pub struct AssistantContext {
-    model: Model,
+    /// The speech recognizer, or `None` if the context only reads text.
+    recognizer: Option<Box<dyn SpeechRecognizer>>,
     config: Config,
     config_paths: Vec<PathBuf>,
+    input: AudioInput,
+    cancel: Arc<AtomicBool>,
+    cues: Box<dyn CuePlayer>,
+    tts: Box<dyn TtsSink>,
+
+    /// The commands run for the last utterance that ran any, with the environment they were run with.
+    last_commands: Option<(Vec<ReadyCommand>, BTreeMap<String, String>)>,
 }
 
 impl AssistantContext {
     pub fn init_from_paths(config_paths: Vec<PathBuf>) -> Result<Self, AssistantRsError> {
         let config = config::cascade_configs(&config_paths)?;
         config.verify()?;
-        let model = build_model(&config.deepspeech_config)?;
+        let recognizer = build_recognizer(&config)?;
+        let cues = build_cue_player(&config);
+        let tts = tts::build_tts_sink(&config.tts_config);
+        Ok(Self {
+            recognizer: Some(recognizer),
+            config,
+            config_paths,
+            input: AudioInput::Microphone,
+            cancel: Arc::default(),
+            cues,
+            tts,
+            last_commands: None,
+        })
+    }
+
+    /// Constructs a context that only reads text, without loading the model or playing cues.
+    ///
+    /// The config does not need a model path.
+    pub fn init_text_from_paths(config_paths: Vec<PathBuf>) -> Result<Self, AssistantRsError> {
+        let config = config::cascade_configs(&config_paths)?;
+        config.modes.verify()?;
+        let tts = tts::build_tts_sink(&config.tts_config);
         Ok(Self {
-            model,
+            recognizer: None,
             config,
             config_paths,
+            input: AudioInput::Microphone,
+            cancel: Arc::default(),
+            cues: Box::new(NullCuePlayer),
+            tts,
+            last_commands: None,
         })
     }
+
+    /// Constructs a context from an already-loaded config and recognizer.
+    ///
+    /// The context has no config files to read from, so `reload` will leave it with an empty config.
+    #[allow(unused)]
+    pub fn new(config: Config, recognizer: Box<dyn SpeechRecognizer>) -> Self {
+        Self {
+            recognizer: Some(recognizer),
+            config,
+            config_paths: Vec::new(),
+            input: AudioInput::Microphone,
+            cancel: Arc::default(),
+            cues: Box::new(NullCuePlayer),
+            tts: Box::new(NullTtsSink),
+            last_commands: None,
+        }
+    }
+
+    /// Sets where the assistant reads its audio from.
+    pub fn with_input(mut self, input: AudioInput) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Sets what plays the assistant's feedback cues.
+    #[cfg(test)]
+    pub fn with_cue_player(mut self, cues: Box<dyn CuePlayer>) -> Self {
+        self.cues = cues;
+        self
+    }
+
+    /// Sets what speaks the assistant's responses.
+    #[cfg(test)]
+    pub fn with_tts_sink(mut self, tts: Box<dyn TtsSink>) -> Self {
+        self.tts = tts;
+        self
+    }
+
+    /// Gets a flag that cancels the current run when set.
+    ///
+    /// The flag is left set once a run is cancelled, so whoever sets it must clear it before the next run.
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
+    }
+
+    /// Re-reads the config files, reloading the model if its config changed.
+    ///
+    /// If the new config cannot be loaded, the current config and model are kept.
+    /// A context that only reads text keeps on doing so.
     pub fn reload(&mut self) -> Result<(), AssistantRsError> {
         let new_conf = config::cascade_configs(&self.config_paths)?;
+        let listens = self.recognizer.is_some();
+        if listens {
+            new_conf.verify()?;
+        } else {
+            new_conf.modes.verify()?;
+        }
         if self.config != new_conf {
             // Only reload the model if we need to
-            if self.config.deepspeech_config != new_conf.deepspeech_config {
-                let new_model = build_model(&new_conf.deepspeech_config)?;
-                self.model = new_model;
+            if listens && self.config.deepspeech_config != new_conf.deepspeech_config {
+                let new_recognizer = build_recognizer(&new_conf)?;
+                self.recognizer = Some(new_recognizer);
+            }
+            let cues_changed = self.config.cue_config != new_conf.cue_config
+                || self.config.audio_config != new_conf.audio_config;
+            if listens && cues_changed {
+                self.cues = build_cue_player(&new_conf);
+            }
+            if self.config.tts_config != new_conf.tts_config {
+                self.tts = tts::build_tts_sink(&new_conf.tts_config);
             }
             self.config = new_conf;
         }
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), AssistantRsError> {
-        let mut cur_mode = run_single(&mut self.model, &self.config, None)?;
+    pub fn run(&mut self) -> Result<RunReport, AssistantRsError> {
+        self.run_in_mode(None)
+    }
+
+    /// Runs the assistant, starting in `mode` instead of the root mode.
+    pub fn run_in_mode(&mut self, mode: Option<&str>) -> Result<RunReport, AssistantRsError> {
+        if let Some(name) = mode {
+            if !self.config.modes.has_mode(name) {
+                return Err(ConfigError::ModeNotFound(name.to_owned()).into());
+            }
+        }
+        let sample_rate = match self.recognizer.as_ref() {
+            Some(recognizer) => recognizer.sample_rate(),
+            None => return Err(AssistantRsError::ModelNotLoaded),
+        };
+        let mut source = self.input.open(sample_rate, &self.config.audio_config)?;
+        self.run_with_source(source.as_mut(), mode)
+    }
+
+    /// Runs the assistant using audio from `source` rather than the configured input.
+    pub fn run_with_source(
+        &mut self,
+        source: &mut dyn AudioSource,
+        mode: Option<&str>,
+    ) -> Result<RunReport, AssistantRsError> {
+        let cancel = Arc::clone(&self.cancel);
+        let source = CancellableSource::new(source, &cancel);
+        self.run_with_utterances(&mut Utterances::Speech(source), mode)
+    }
+
+    /// Runs the assistant, taking each of `lines` as the transcript of an utterance instead of listening.
+    ///
+    /// The run ends early if it runs out of lines.
+    pub fn run_with_text(
+        &mut self,
+        lines: &mut dyn Iterator<Item = String>,
+        mode: Option<&str>,
+    ) -> Result<RunReport, AssistantRsError> {
+        if let Some(name) = mode {
+            if !self.config.modes.has_mode(name) {
+                return Err(ConfigError::ModeNotFound(name.to_owned()).into());
+            }
+        }
+        self.run_with_utterances(&mut Utterances::Text(lines), mode)
+    }
+
+    fn run_with_utterances(
+        &mut self,
+        source: &mut Utterances,
+        mode: Option<&str>,
+    ) -> Result<RunReport, AssistantRsError> {
+        let mut report = RunReport::default();
+
+        // The modes entered during the run, so that `Action::Back` can return to the previous one.
+        let mut modes: Vec<String> = mode.map(|s| s.to_owned()).into_iter().collect();
         log::log!(log::Level::Debug, "Starting run.");
-        while cur_mode.is_some() {
-            log::log!(
-                log::Level::Debug,
-                "Next mode: {}",
-                cur_mode.as_ref().unwrap()
-            );
-            cur_mode = run_single(
-                &mut self.model,
+        play_cue(self.cues.as_mut(), source, Cue::Listening);
+        loop {
+            report.final_mode = modes.last().cloned();
+            let cur_mode = modes.last().map(|s| s.as_ref());
+            let res = run_single(
+                self.recognizer.as_mut().map(|r| r.as_mut() as _),
                 &self.config,
-                cur_mode.as_ref().map(|s| s.as_ref()),
-            )?;
+                source,
+                &modes,
+                &mut report,
+            );
+            let step = match (res, self.config.modes.timeout_for_mode(cur_mode)) {
+                (Err(AssistantRsError::ListenTimeout(_)), Some((timeout, on_timeout))) => {
+                    log::log!(log::Level::Info, "No speech heard within {:?}.", timeout);
+                    report.timed_out = true;
+                    match on_timeout {
+                        OnTimeout::End => break,
+                        OnTimeout::Root => {
+                            modes.clear();
+                            play_cue(self.cues.as_mut(), source, Cue::Listening);
+                            continue;
+                        }
+                    }
+                }
+                (Err(AssistantRsError::EndOfInput), _) => break,
+                (res, _) => res?,
+            };
+            play_cue(self.cues.as_mut(), source, cue_for_step(&step, &modes));
+            for text in step.say.iter() {
+                say(self.tts.as_mut(), source, text, &mut report);
+            }
+            if let Some(action) = step.action {
+                log::log!(log::Level::Debug, "Running action: {:?}", action);
+                report.actions.push(action);
+            }
+
+            // Repeat the previous commands before this utterance's own replace them.
+            if step.action == Some(Action::RepeatLast) {
+                self.repeat_last(&mut report)?;
+            }
+            if !step.commands.is_empty() {
+                self.last_commands = Some((step.commands, step.env));
+            }
+            match step.action {
+                Some(Action::Reload) => {
+                    self.reload()?;
+                    break;
+                }
+                Some(Action::Cancel) | Some(Action::ExitDaemon) => break,
+                Some(Action::Back) => {
+                    if modes.pop().is_none() {
+                        break;
+                    }
+                    let tree = &self.config.modes;
+                    if let Some(prompt) = modes.last().and_then(|m| tree.prompt_for_mode(m)) {
+                        say(self.tts.as_mut(), source, prompt, &mut report);
+                    }
+                    continue;
+                }
+                Some(Action::ListCommands) => {
+                    self.list_commands(modes.last().map(|s| s.as_ref()));
+                    continue;
+                }
+                Some(Action::RepeatLast) | None => {}
+            }
+            match step.next_mode {
+                Some(next) => {
+                    log::log!(log::Level::Debug, "Next mode: {}", next);
+                    if let Some(prompt) = self.config.modes.prompt_for_mode(&next) {
+                        say(self.tts.as_mut(), source, prompt, &mut report);
+                    }
+
+                    // Every mode passed through on the way counts, so that `back` returns to the one before.
+                    modes.extend(step.modes);
+                }
+                None => break,
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs the commands from the last utterance that ran any again.
+    fn repeat_last(&self, report: &mut RunReport) -> Result<(), AssistantRsError> {
+        let (commands, env) = match self.last_commands.as_ref() {
+            Some(last) => last,
+            None => {
+                log::log!(log::Level::Info, "There are no commands to repeat.");
+                return Ok(());
+            }
+        };
+        for cmd in commands {
+            cmd.run(env, report)?;
         }
         Ok(())
     }
+
+    /// Logs every message that can be said while listening in `mode`.
+    fn list_commands(&self, mode: Option<&str>) {
+        let messages: Vec<_> = self
+            .config
+            .modes
+            .commands_for_mode(mode)
+            .flat_map(|cmd| cmd.messages())
+            .filter(|msg| !msg.trim().is_empty())
+            .collect();
+        log::log!(
+            log::Level::Info,
+            "Commands in {}: {}",
+            mode.unwrap_or("the root mode"),
+            messages.join(", ")
+        );
+    }
 }
 
-/// Processes a single utterance instance to completion.
-/// If this returns `Ok(None)`, then the run is complete; otherwise,
-/// the run should continue using the returned `String` as
+/// Where the transcripts of utterances come from during a run.
+enum Utterances<'a> {
+    /// Speech transcribed from audio.
+    Speech(CancellableSource<'a>),
+
+    /// Lines of text, taken as already transcribed.
+    Text(&'a mut dyn Iterator<Item = String>),
+}
+
+impl<'a> Utterances<'a> {
+    /// Waits for the next utterance, returning its transcript.
+    ///
+    /// Errors with `AssistantRsError::EndOfInput` once there are no more lines of text.
+    fn next_transcript(
+        &mut self,
+        recognizer: Option<&mut dyn SpeechRecognizer>,
+        listen_config: &ListenConfig,
+    ) -> Result<String, AssistantRsError> {
+        match self {
+            Utterances::Speech(source) => {
+                let recognizer = recognizer.ok_or(AssistantRsError::ModelNotLoaded)?;
+                get_raw_utterance(recognizer, source, listen_config)
+            }
+            Utterances::Text(lines) => lines.next().ok_or(AssistantRsError::EndOfInput),
+        }
+    }
+
+    /// Throws away any audio recorded but not yet transcribed.
+    fn discard_buffered(&mut self) {
+        if let Utterances::Speech(source) = self {
+            source.discard_buffered();
+        }
+    }
+}
+
+/// What happened after a single utterance, for the context to act on.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Step {
+    /// Whether the utterance matched a command.
+    matched: bool,
+
+    /// The mode to keep listening in, or `None` if the run is complete.
+    next_mode: Option<String>,
+
+    /// Every mode entered along the matched path, in order, ending with `next_mode` if there is one.
+    modes: Vec<String>,
+
+    /// The built-in action that was matched, if any.
+    action: Option<Action>,
+
+    /// The terminal commands that were run, with their slots filled in.
+    commands: Vec<ReadyCommand>,
+
+    /// The environment the commands were run with.
+    env: BTreeMap<String, String>,
+
+    /// What to say about the utterance, in order.
+    say: Vec<String>,
+}
+
+/// Processes a single utterance instance to completion, recording what happened in `report`.
+/// The utterance is heard in the last of `modes`, the modes entered during the run so far.
+/// If the returned step has no `next_mode`, then the run is complete; otherwise,
+/// the run should continue using that as
 /// the name of the new root mode.
 fn run_single(
-    model: &mut Model,
+    recognizer: Option<&mut dyn SpeechRecognizer>,
     config: &Config,
-    current_mode: Option<&str>,
-) -> Result<Option<String>, AssistantRsError> {
+    source: &mut Utterances,
+    modes: &[String],
+    report: &mut RunReport,
+) -> Result<Step, AssistantRsError> {
+    let current_mode = modes.last().map(|s| s.as_ref());
+
+    // Modes with their own timeout stop waiting for speech after it, rather than the usual start timeout.
+    let listen_config = match config.modes.timeout_for_mode(current_mode) {
+        Some((timeout, _)) => ListenConfig {
+            start_timeout_ms: Some(timeout.as_millis() as u64),
+            ..config.listen_config.clone()
+        },
+        None => config.listen_config.clone(),
+    };
+
     // Get the raw transcription of the audio.
-    let final_msg = get_raw_utterance(model)?;
+    let final_msg = source.next_transcript(recognizer, &listen_config)?;
     let final_msg = final_msg.trim();
     log::log!(log::Level::Debug, "Finished message: {}", final_msg);
+    report.transcripts.push(final_msg.to_owned());
+
+    // Match the command, currently via minimum phonetic edit distance.
+    let matched = match match_commands(&config.modes, current_mode, final_msg) {
+        Some(matched) => matched,
+        None => {
+            log::log!(
+                log::Level::Info,
+                "No command matched message: {}",
+                final_msg
+            );
+            report.matched = false;
+            if let Some(cmd) = config.modes.on_no_match() {
+                let cmd = ReadyCommand {
+                    line: CommandLine::Shell(cmd.to_owned()),
+                    policy: ExecPolicy::default(),
+                };
+                cmd.run(&command_env(final_msg, modes, None), report)?;
+            }
+            return Ok(Step::default());
+        }
+    };
+    log::log!(
+        log::Level::Debug,
+        "Command buff: {:?}",
+        matched.command_lines()
+    );
+    log::log!(log::Level::Debug, "Returned mode: {:?}", matched.next_mode);
+    log::log!(log::Level::Debug, "Match distance: {}", matched.distance);
+    log::log!(log::Level::Debug, "Slot values: {:?}", matched.slots);
+    report.matched = true;
+    if matched.action == Some(Action::Cancel) {
+        // Nothing else along a cancelled path is run or said, other than the cancelling command's own response.
+        let say = matched
+            .commands
+            .iter()
+            .filter(|cmd| cmd.action() == Some(Action::Cancel))
+            .filter_map(|cmd| cmd.say())
+            .map(|text| slots::substitute(text, &matched.slots))
+            .collect();
+        return Ok(Step {
+            matched: true,
+            action: matched.action,
+            say,
+            ..Step::default()
+        });
+    }
+
+    // Run the matched commands, collecting what to say about them.
+    let env = command_env(final_msg, modes, Some(&matched));
+    let mut commands = Vec::with_capacity(matched.commands.len());
+    let mut say = Vec::new();
+    for cmd in matched.commands.iter() {
+        if let Some(text) = cmd.say() {
+            say.push(slots::substitute(text, &matched.slots));
+        }
+        if cmd.command().is_none() {
+            continue;
+        }
+        let ready = ReadyCommand::new(cmd, &matched.slots);
+        let status = ready.run(&env, report)?;
+        if ready.policy.say_output() {
+            let output = status.stdout.unwrap_or_default();
+            if !output.trim().is_empty() {
+                say.push(output.trim().to_owned());
+            }
+        }
+        commands.push(ready);
+    }
+    report.slots.extend(matched.slots);
+
+    Ok(Step {
+        matched: true,
+        next_mode: matched.next_mode,
+        modes: matched.modes,
+        action: matched.action,
+        commands,
+        env,
+        say,
+    })
+}
+
+/// Picks the cue that tells the speaker what happened after `step`, while listening in the last of `modes`.
+///
+/// If the run keeps listening in another mode, that takes priority over the command having matched.
+fn cue_for_step(step: &Step, modes: &[String]) -> Cue {
+    if !step.matched {
+        return Cue::NoMatch;
+    }
+    match step.action {
+        Some(Action::Back) if !modes.is_empty() => Cue::ModeEntered,
+        Some(Action::ListCommands) => Cue::Listening,
+        Some(Action::Reload)
+        | Some(Action::Cancel)
+        | Some(Action::ExitDaemon)
+        | Some(Action::Back) => Cue::Matched,
+        Some(Action::RepeatLast) | None if step.next_mode.is_some() => Cue::ModeEntered,
+        Some(Action::RepeatLast) | None => Cue::Matched,
+    }
+}
+
+/// Plays `cue`, only logging any error so that a missing speaker does not stop the assistant.
+///
+/// Whatever `source` recorded while the cue played is thrown away, so that the cue is not heard as speech.
+fn play_cue(cues: &mut dyn CuePlayer, source: &mut Utterances, cue: Cue) {
+    if let Err(e) = cues.play(cue) {
+        log::log!(
+            log::Level::Warn,
+            "Error playing the {:?} cue: {}",
+            cue,
+            error_message(&e)
+        );
+    } else if cues.is_audible() {
+        source.discard_buffered();
+    }
+}
+
+/// Says `text`, recording it in `report` and only logging any error so that a broken `tts-command` does not
+/// stop the assistant.
+///
+/// Whatever `source` recorded while the text was spoken is thrown away, so that it is not heard as speech.
+fn say(tts: &mut dyn TtsSink, source: &mut Utterances, text: &str, report: &mut RunReport) {
+    report.spoken.push(text.to_owned());
+    if let Err(e) = tts.say(text) {
+        log::log!(
+            log::Level::Warn,
+            "Error saying \"{}\": {}",
+            text,
+            error_message(&e)
+        );
+    } else if tts.is_audible() {
+        source.discard_buffered();
+    }
+}
 
-    // Match the command, currently via minimum edit distance.
-    let (commands, next_mode) = match_commands(&config.modes, current_mode, final_msg);
-    log::log!(log::Level::Debug, "Command buff: {:?}", commands);
-    log::log!(log::Level::Debug, "Returned mode: {:?}", next_mode);
-    // Run the matched commands.
-    for cmd in commands.into_iter() {
-        run_command(&cmd)?;
+/// Builds the environment variables that tell a command why it was run.
+///
+/// `modes` are the modes entered during the run, and `matched` is `None` for the `on-no-match` command.
+/// The mode path also includes the modes entered along the matched path before its last message.
+fn command_env(
+    transcript: &str,
+    modes: &[String],
+    matched: Option<&CommandMatch>,
+) -> BTreeMap<String, String> {
+    let mut retvl = BTreeMap::new();
+    retvl.insert("ASSISTANT_TRANSCRIPT".to_owned(), transcript.to_owned());
+    let heard_in = matched.map_or(&[][..], |m| m.modes_before_last());
+    let mode_path: Vec<_> = modes.iter().chain(heard_in).map(|s| s.as_str()).collect();
+    retvl.insert("ASSISTANT_MODE_PATH".to_owned(), mode_path.join("/"));
+    if let Some(matched) = matched {
+        retvl.insert(
+            "ASSISTANT_MATCHED_MESSAGE".to_owned(),
+            matched.messages.join(" "),
+        );
+        retvl.insert(
+            "ASSISTANT_MATCH_DISTANCE".to_owned(),
+            matched.distance.to_string(),
+        );
+        let slot_vars = matched
+            .slots
+            .iter()
+            .map(|(name, value)| (slots::env_var_name(name), value.to_owned()));
+        retvl.extend(slot_vars);
+    }
+    retvl
+}
+
+/// Builds the player for the config's feedback cues.
+///
+/// If cues are disabled, or the speaker cannot be used, the cues are silent.
+fn build_cue_player(conf: &Config) -> Box<dyn CuePlayer> {
+    if !conf.cue_config.feedback_cues() {
+        return Box::new(NullCuePlayer);
+    }
+    match CpalCuePlayer::new(&conf.audio_config, &conf.cue_config) {
+        Ok(player) => Box::new(player),
+        Err(e) => {
+            log::log!(
+                log::Level::Warn,
+                "Cannot play feedback cues: {}",
+                error_message(&e)
+            );
+            Box::new(NullCuePlayer)
+        }
     }
+}
+
+/// Loads the speech recognizer from the config.
+fn build_recognizer(conf: &Config) -> Result<Box<dyn SpeechRecognizer>, AssistantRsError> {
+    let recognizer = DeepspeechRecognizer::from_config(&conf.deepspeech_config)?;
+    Ok(Box::new(recognizer))
+}
+
+/// A path through the mode config graph matched from a raw speech string.
+#[derive(Debug, Clone, PartialEq)]
+struct CommandMatch<'a> {
+    /// The commands along the path that have terminal commands or responses, in the order they should be run.
+    commands: Vec<&'a Command>,
+
+    /// The messages or aliases matched along the path, as written in the config.
+    messages: Vec<&'a str>,
 
-    Ok(next_mode)
+    /// The mode to keep listening in, or `None` if the run is complete.
+    next_mode: Option<String>,
+
+    /// Every mode entered along the path, in order, ending with `next_mode` if there is one.
+    modes: Vec<String>,
+
+    /// The last built-in action along the path, if any.
+    action: Option<Action>,
+
+    /// The normalized phonetic distance between the speech and the path's messages.
+    distance: f32,
+
+    /// The values captured by the slots in the path's messages.
+    slots: SlotValues,
 }
 
-/// Loads the DeepSpeech model from the config.
-fn build_model(conf: &DeepspeechConfig) -> Result<Model, AssistantRsError> {
-    let lib = conf.library_path()?;
-    let model = conf.model_path()?;
-    let mut retvl = Model::load_from_files(lib.as_ref(), model.as_ref())?;
-    if let Some(scorer) = conf.scorer_path()? {
-        retvl.enable_external_scorer(scorer)?;
+impl<'a> CommandMatch<'a> {
+    /// The modes entered along the path before its last message, which that message was heard in.
+    fn modes_before_last(&self) -> &[String] {
+        match (self.next_mode.as_ref(), self.modes.split_last()) {
+            (Some(_), Some((_, before))) => before,
+            _ => &self.modes,
+        }
     }
-    if let Some(w) = conf.beam_width()? {
-        retvl.set_model_beam_width(w)?;
+
+    /// The terminal commands along the path, before their slots are filled in.
+    fn command_lines(&self) -> Vec<String> {
+        self.commands
+            .iter()
+            .filter_map(|cmd| cmd.command())
+            .map(|line| line.to_string())
+            .collect()
     }
-    Ok(retvl)
 }
 
-/// Attempts to match a raw speech string to a "path" in the mode config graph
+/// Attempts to match a raw speech string to a "path" in the mode config graph.
+///
+/// Candidate paths are compared to the speech by their pronounciation, via `metrics::phone_dist`.
+/// Returns `None` if even the best path is further from the speech than the mode's match threshold.
 fn match_commands<'a>(
     conf: &'a crate::modes::ModeTree,
     current_mode: Option<&str>,
     raw_text: &str,
-) -> (Vec<&'a str>, Option<String>) {
+) -> Option<CommandMatch<'a>> {
+    let matched = walk_commands(conf, current_mode, raw_text, None)?;
+    if let Some(threshold) = conf.threshold_for_mode(current_mode) {
+        if matched.distance > threshold {
+            return None;
+        }
+    }
+    Some(matched)
+}
+
+/// Finds the best path through the mode config graph for a raw speech string, like `match_commands`,
+/// but without rejecting paths that are too far from the speech.
+///
+/// If `trace` is given, every candidate considered at each step of the walk is recorded in it.
+fn walk_commands<'a>(
+    conf: &'a crate::modes::ModeTree,
+    current_mode: Option<&str>,
+    raw_text: &str,
+    mut trace: Option<&mut Vec<MatchStep>>,
+) -> Option<CommandMatch<'a>> {
+    let text = SpokenWords::new_in(raw_text, conf.pronunciations());
     let mut mode = current_mode;
     let mut command_buff = Vec::new();
-    let mut str_buff = "".to_owned();
+    let mut messages = Vec::new();
+    let mut modes = Vec::new();
+    let mut action = None;
+    let mut path = MessagePattern::default();
+    let mut path_len = 0;
     loop {
         // Get all the edges from this node
         let current_commands = conf.commands_for_mode(mode);
 
-        // Tries to match the next edge from the current
-        let mut matched_cmd: Option<&Command> = None;
-        let mut matched_cmd_dist = crate::metrics::leven_dist(&str_buff, raw_text);
+        // Tries to match the next edge from the current, along with the message that matched
+        let mut matched_cmd: Option<(&Command, &str, &MessagePattern)> = None;
+        let mut matched_cmd_dist = metrics::pattern_dist(&path, &text).map(|m| m.distance);
+        let mut step = MatchStep {
+            mode: mode.map(|s| s.to_owned()),
+            stop_distance: matched_cmd_dist,
+            ..MatchStep::default()
+        };
         for cur in current_commands {
             // If the message is blank, this is the "default" end command.
             // Only run it if we didn't already find a better match.
             if cur.message().trim().is_empty() {
+                step.candidates
+                    .push(MatchCandidate::new(cur.message(), None));
                 if matched_cmd.is_none() {
-                    matched_cmd = Some(cur);
+                    matched_cmd = Some((cur, cur.message(), cur.pattern()));
                 }
                 continue;
             }
 
-            let cur_msg = format!("{} {}", str_buff, cur.message());
-            let cur_dist = metrics::leven_dist(&cur_msg, raw_text);
-            let is_initial_cmd = str_buff.is_empty() && matched_cmd.is_none();
-            if cur_dist < matched_cmd_dist || is_initial_cmd {
-                matched_cmd = Some(cur);
-                matched_cmd_dist = cur_dist;
+            // Every alias is scored, and the best one counts for the command.
+            for (message, pattern) in cur.messages().zip(cur.patterns()) {
+                // Messages whose slots cannot be filled from the text are never matched.
+                let cur_path = path.join(pattern);
+                let cur_dist = metrics::pattern_dist(&cur_path, &text).map(|m| m.distance);
+                step.candidates.push(MatchCandidate::new(message, cur_dist));
+                let cur_dist = match cur_dist {
+                    Some(d) => d,
+                    None => continue,
+                };
+                let is_initial_cmd = path.is_empty() && matched_cmd.is_none();
+                let is_closer = match matched_cmd_dist {
+                    Some(d) => cur_dist < d,
+                    None => true,
+                };
+                if is_closer || is_initial_cmd {
+                    matched_cmd = Some((cur, message, pattern));
+                    matched_cmd_dist = Some(cur_dist);
+                }
             }
         }
 
+        step.chosen = matched_cmd.map(|(_, message, _)| message.to_owned());
+        if let Some(trace) = trace.as_mut() {
+            trace.push(step);
+        }
+
         // If we moved along an edge to a new node, record the next command and path component
-        if let Some(cmd) = matched_cmd {
-            if let Some(term_cmd) = cmd.command() {
-                command_buff.push(term_cmd);
+        if let Some((cmd, message, pattern)) = matched_cmd {
+            if cmd.command().is_some() || cmd.say().is_some() {
+                command_buff.push(cmd);
             }
-            mode = cmd.next_mode();
-            if mode.is_some() {
-                str_buff.push(' ');
-                str_buff.push_str(cmd.message());
+            if !message.trim().is_empty() {
+                messages.push(message);
             }
+            action = cmd.action().or(action);
+            mode = cmd.next_mode();
+            modes.extend(mode.map(|m| m.to_owned()));
+            path = path.join(pattern);
+            path_len += 1;
         }
         // If we did not progress or progressed to a terminal node, break
         if matched_cmd.is_none() || mode.is_none() {
             break;
         }
     }
-    (command_buff, mode.map(|s| s.to_owned()))
-}
+    if path_len == 0 {
+        return None;
+    }
 
-fn build_audio_stream(sample_rate: u32) -> Result<AudioReciever, AssistantRsError> {
-    //TODO: Allow input configuration.
-    let host = cpal::default_host();
-    let dev = host
-        .default_input_device()
-        .ok_or(AssistantRsError::MicrophoneNotFound)?;
-    let stream_conf = cpal::StreamConfig {
-        channels: 1,
-        sample_rate: cpal::SampleRate(sample_rate),
+    // Paths made only of blank "default" commands are meant to match anything.
+    let (distance, slots) = if path.is_empty() {
+        (0.0, SlotValues::new())
+    } else {
+        metrics::normalized_pattern_dist(&path, &text)?
     };
-    let res = AudioReciever::construct(&dev, &stream_conf);
-    let try_other = matches!(
-        res,
-        Err(AssistantRsError::Cpal(CpalError::BuildStream(
-            BuildStreamError::StreamConfigNotSupported
-        )))
-    );
-    if !try_other {
-        return res;
+    Some(CommandMatch {
+        commands: command_buff,
+        messages,
+        next_mode: mode.map(|s| s.to_owned()),
+        modes,
+        action,
+        distance,
+        slots,
+    })
+}
+
+/// A step of the walk through the mode config graph, as recorded by `walk_commands`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MatchStep {
+    /// The mode whose commands were considered, or `None` for the root mode.
+    pub mode: Option<String>,
+
+    /// The phonetic distance between the speech and the path so far, if the walk stopped before this step.
+    pub stop_distance: Option<usize>,
+
+    /// Every message and alias that could have been matched next.
+    pub candidates: Vec<MatchCandidate>,
+
+    /// The message that was matched, or `None` if the walk stopped.
+    pub chosen: Option<String>,
+}
+
+/// A message considered during a step of the walk through the mode config graph.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MatchCandidate {
+    /// The message or alias, as written in the config.
+    pub message: String,
+
+    /// The phonetic distance between the speech and the path with this message added to it.
+    ///
+    /// `None` for a blank "default" message, or if the message's slots cannot be filled from the speech.
+    pub distance: Option<usize>,
+}
+
+impl MatchCandidate {
+    fn new(message: &str, distance: Option<usize>) -> Self {
+        Self {
+            message: message.to_owned(),
+            distance,
+        }
     }
+}
+
+/// How a raw speech string is matched against the commands, explained step by step.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MatchExplanation {
+    /// The speech that was matched.
+    pub transcript: String,
+
+    /// The mode the match started in, or `None` for the root mode.
+    pub start_mode: Option<String>,
 
-    // If the default input does not support what we need, find one that does.
-    let format = cpal::SampleFormat::I16;
-    let host_ids = cpal::available_hosts().into_iter();
-    let working_hosts = host_ids.filter_map(|id| cpal::host_from_id(id).ok());
+    /// Each step of the walk through the mode config graph.
+    pub steps: Vec<MatchStep>,
 
-    // Get an iterator over all inputs that support what we need
-    let mut valid_inputs = working_hosts
-        .filter_map(|host| host.input_devices().ok())
-        .flatten()
-        .filter(|dev| dev.input_supports(&stream_conf, format).unwrap_or(false));
+    /// The normalized distance between the speech and the best path, as compared to the match threshold.
+    pub distance: Option<f32>,
 
-    // Return the first usable, or `MicrophoneNotFound` on error.
-    valid_inputs
-        .find_map(|dev| AudioReciever::construct(&dev, &stream_conf).ok())
-        .ok_or(AssistantRsError::MicrophoneNotFound)
+    /// The match threshold in the starting mode, if there is one.
+    pub threshold: Option<f32>,
+
+    /// Whether the best path would be run.
+    pub matched: bool,
+
+    /// The terminal commands along the best path, with their slots filled in.
+    pub commands: Vec<String>,
+
+    /// The built-in action along the best path, if any.
+    pub action: Option<Action>,
+
+    /// The mode the assistant would keep listening in, or `None` if the run would be complete.
+    pub final_mode: Option<String>,
+
+    /// The values captured by the slots in the best path's messages.
+    pub slots: SlotValues,
 }
 
-fn get_raw_utterance(model: &mut Model) -> Result<String, AssistantRsError> {
-    // Construct the speech loader and audio reciever.
-    let sample_rate = model.get_sample_rate();
-    let mut loader = SpeechLoader::new(model.create_stream()?, sample_rate as u32);
-    let audio_recv = build_audio_stream(model.get_sample_rate().abs() as u32)?;
+/// Explains how `raw_text` would be matched against the commands while listening in `mode`,
+/// without running anything.
+pub fn explain_match(
+    conf: &crate::modes::ModeTree,
+    mode: Option<&str>,
+    raw_text: &str,
+) -> MatchExplanation {
+    let raw_text = raw_text.trim();
+    let mut retvl = MatchExplanation {
+        transcript: raw_text.to_owned(),
+        start_mode: mode.map(|s| s.to_owned()),
+        threshold: conf.threshold_for_mode(mode),
+        ..MatchExplanation::default()
+    };
+    let matched = match walk_commands(conf, mode, raw_text, Some(&mut retvl.steps)) {
+        Some(matched) => matched,
+        None => return retvl,
+    };
+    retvl.distance = Some(matched.distance);
+    retvl.matched = match retvl.threshold {
+        Some(threshold) => matched.distance <= threshold,
+        None => true,
+    };
+    retvl.commands = matched
+        .commands
+        .iter()
+        .filter(|cmd| cmd.command().is_some())
+        .map(|cmd| ReadyCommand::new(cmd, &matched.slots).line.to_string())
+        .collect();
+    retvl.action = matched.action;
+    retvl.final_mode = matched.next_mode;
+    retvl.slots = matched.slots;
+    retvl
+}
+
+impl fmt::Display for MatchExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode_name = |mode: &Option<String>| match mode {
+            Some(mode) => format!("mode {}", mode),
+            None => "the root mode".to_owned(),
+        };
+        let score = |distance: Option<usize>| match distance {
+            Some(d) => d.to_string(),
+            None => "-".to_owned(),
+        };
+        writeln!(f, "Transcript: {}", self.transcript)?;
+        for (idx, step) in self.steps.iter().enumerate() {
+            writeln!(
+                f,
+                "Step {} in {} (stopping here scores {}):",
+                idx + 1,
+                mode_name(&step.mode),
+                score(step.stop_distance)
+            )?;
+            for candidate in step.candidates.iter() {
+                let marker = if step.chosen.as_ref() == Some(&candidate.message) {
+                    "*"
+                } else {
+                    " "
+                };
+                let message = if candidate.message.trim().is_empty() {
+                    "<default>"
+                } else {
+                    &candidate.message
+                };
+                writeln!(
+                    f,
+                    "  {} {:>5}  {}",
+                    marker,
+                    score(candidate.distance),
+                    message
+                )?;
+            }
+        }
+        let distance = match self.distance {
+            Some(d) => format!("{:.3}", d),
+            None => "-".to_owned(),
+        };
+        let threshold = match self.threshold {
+            Some(t) => format!("{:.3}", t),
+            None => "none".to_owned(),
+        };
+        writeln!(f, "Distance: {} (threshold {})", distance, threshold)?;
+        if !self.matched {
+            return writeln!(f, "No command matched.");
+        }
+        writeln!(f, "Commands: {}", self.commands.join("; "))?;
+        if let Some(action) = self.action {
+            writeln!(f, "Action: {:?}", action)?;
+        }
+        match self.final_mode.as_ref() {
+            Some(mode) => writeln!(f, "Keeps listening in mode {}", mode),
+            None => writeln!(f, "The run ends."),
+        }
+    }
+}
+
+/// Listens to `audio_recv` until the speaker finishes a single utterance, returning its transcript.
+///
+/// Errors with `AssistantRsError::ListenTimeout` if nobody starts speaking within the configured
+/// start timeout. If the utterance runs past the configured maximum length, whatever was heard
+/// until then is returned.
+fn get_raw_utterance(
+    recognizer: &mut dyn SpeechRecognizer,
+    audio_recv: &mut dyn AudioSource,
+    listen_config: &ListenConfig,
+) -> Result<String, AssistantRsError> {
+    if listen_config.vad() {
+        return get_vad_utterance(recognizer, audio_recv, listen_config);
+    }
+
+    // Construct the speech loader.
+    let sample_rate = recognizer.sample_rate();
+    let mut loader = SpeechLoader::new(recognizer.create_stream()?, sample_rate);
+    let end_silence = listen_config.end_silence();
 
     // Listen for the command until the command is over.
     loop {
@@ -208,36 +1022,336 @@ fn get_raw_utterance(model: &mut Model) -> Result<String, AssistantRsError> {
             loader.current_text()
         );
         let has_started = !loader.current_text().is_empty();
-        let has_finished = has_started && loader.time_since_change() > Duration::from_millis(100);
+        let has_finished = has_started && loader.time_since_change() > end_silence;
 
         if has_finished {
             break;
         }
+        if let Some(timeout) = listen_config.start_timeout() {
+            if !has_started && loader.time_listened() >= timeout {
+                return Err(AssistantRsError::ListenTimeout(timeout));
+            }
+        }
+        if let Some(max_len) = listen_config.max_utterance() {
+            if loader.time_listened() >= max_len {
+                log::log!(
+                    log::Level::Warn,
+                    "Utterance reached the maximum length of {:?}.",
+                    max_len
+                );
+                break;
+            }
+        }
         let l = audio_recv.wait_until(sample_rate as usize)?;
+        if l.is_empty() {
+            // The source ran out of audio, so the speech is as finished as it will get.
+            break;
+        }
         loader.push(&l)?;
     }
 
     // Get the raw transcription of the audio.
-    let (final_msg, _) = loader.finish();
+    let (final_msg, _) = loader.finish()?;
     Ok(final_msg)
 }
 
-fn run_command(cmd: &str) -> Result<(), AssistantRsError> {
-    let raw_command = cmd;
-    process::Command::new("sh")
-        .arg("-c")
-        .arg(raw_command)
-        .stderr(process::Stdio::null())
-        .stdout(process::Stdio::null())
-        .stdin(process::Stdio::null())
-        .spawn()?;
-    Ok(())
+/// Like `get_raw_utterance`, but uses a `VoiceDetector` to decide when speech starts and ends.
+///
+/// The recognizer is only fed audio containing speech, and only decodes it once the speech ends.
+/// If the recognizer hears no words in what the detector thought was speech, the run keeps listening.
+fn get_vad_utterance(
+    recognizer: &mut dyn SpeechRecognizer,
+    audio_recv: &mut dyn AudioSource,
+    listen_config: &ListenConfig,
+) -> Result<String, AssistantRsError> {
+    let sample_rate = recognizer.sample_rate();
+    let mut vad = VoiceDetector::new(
+        sample_rate,
+        listen_config.vad_threshold_db(),
+        listen_config.vad_preroll(),
+    );
+    let end_silence = listen_config.end_silence();
+
+    // Read audio in small chunks so that the end of speech is noticed promptly.
+    let chunk_len = (sample_rate as usize / 10).max(1);
+    let mut samples_listened = 0;
+
+    // The loader is only constructed once speech starts.
+    let mut loader: Option<SpeechLoader> = None;
+    loop {
+        let listened = buffer::samples_to_duration(samples_listened, sample_rate);
+        if let Some(timeout) = listen_config.start_timeout() {
+            if loader.is_none() && listened >= timeout {
+                return Err(AssistantRsError::ListenTimeout(timeout));
+            }
+        }
+        let has_finished = vad.speech_started() && vad.time_since_speech() > end_silence;
+        let too_long = listen_config
+            .max_utterance()
+            .filter(|max_len| listened >= *max_len);
+        if let Some(max_len) = too_long {
+            log::log!(
+                log::Level::Warn,
+                "Utterance reached the maximum length of {:?}.",
+                max_len
+            );
+        }
+        if has_finished || too_long.is_some() {
+            let final_msg = match loader.take() {
+                Some(l) => l.finish()?.0,
+                None => String::new(),
+            };
+            if too_long.is_some() || !final_msg.trim().is_empty() {
+                return Ok(final_msg);
+            }
+            log::log!(log::Level::Debug, "Heard noise, but no speech.");
+            vad.reset();
+        }
+
+        let l = audio_recv.wait_until(chunk_len)?;
+        if l.is_empty() {
+            // The source ran out of audio, so the speech is as finished as it will get.
+            break;
+        }
+        samples_listened += l.len();
+        let speech = vad.process(&l);
+        if !speech.is_empty() {
+            if loader.is_none() {
+                loader = Some(SpeechLoader::new(recognizer.create_stream()?, sample_rate));
+            }
+            if let Some(l) = loader.as_mut() {
+                l.feed(&speech);
+            }
+        }
+    }
+
+    match loader {
+        Some(l) => Ok(l.finish()?.0),
+        None => Ok(String::new()),
+    }
+}
+
+/// A terminal command with its slots filled in, along with how to run it.
+#[derive(Debug, Clone, PartialEq)]
+struct ReadyCommand {
+    line: CommandLine,
+    policy: ExecPolicy,
+}
+
+impl ReadyCommand {
+    fn new(cmd: &Command, slots: &SlotValues) -> Self {
+        let line = match cmd.command() {
+            Some(line) => line.substitute(slots),
+            None => CommandLine::Argv(Vec::new()),
+        };
+        Self {
+            line,
+            policy: cmd.exec_policy().clone(),
+        }
+    }
+
+    /// Runs the command with `env` added to its environment, and records it in `report`.
+    fn run(
+        &self,
+        env: &BTreeMap<String, String>,
+        report: &mut RunReport,
+    ) -> Result<CommandStatus, AssistantRsError> {
+        let status = exec::run_command(&self.line, &self.policy, env)?;
+        report.commands.push(status.command.clone());
+        report.command_statuses.push(status.clone());
+        Ok(status)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::modes::{Command, CommandMessage, ModeTree};
+    use crate::testing::{
+        temp_path, tone, RecordingCuePlayer, RecordingTtsSink, SampleSource, ScriptedRecognizer,
+        SilentSource,
+    };
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    /// Commands are spawned in the background, so give them some time to finish.
+    fn wait_for_file(path: &Path) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if path.exists() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_raw_utterance_end_of_speech() {
+        let mut recognizer = ScriptedRecognizer::new(16000).with_script(vec![
+            (16000, "fire".to_owned()),
+            (32000, "fire fox".to_owned()),
+        ]);
+        let listen_config = ListenConfig::default();
+        let mut source = SilentSource::new(16000 * 60);
+        let msg = get_raw_utterance(&mut recognizer, &mut source, &listen_config).unwrap();
+        assert_eq!(msg, "fire fox");
+
+        // Silence without any speech runs until the source is exhausted.
+        let mut source = SilentSource::new(16000 * 5);
+        let msg = get_raw_utterance(&mut recognizer, &mut source, &listen_config).unwrap();
+        assert_eq!(msg, "");
+    }
+
+    #[test]
+    fn test_raw_utterance_timeouts() {
+        let listen_config = ListenConfig {
+            end_silence_ms: Some(2500),
+            max_utterance_ms: Some(6000),
+            start_timeout_ms: Some(3000),
+            ..ListenConfig::default()
+        };
+
+        // Long pauses mid-sentence are not the end of speech.
+        let mut recognizer = ScriptedRecognizer::new(16000).with_script(vec![
+            (16000, "fire".to_owned()),
+            (48000, "fire fox".to_owned()),
+        ]);
+        let mut source = SilentSource::new(16000 * 60);
+        let msg = get_raw_utterance(&mut recognizer, &mut source, &listen_config).unwrap();
+        assert_eq!(msg, "fire fox");
+
+        // Nobody started speaking.
+        let mut recognizer = ScriptedRecognizer::new(16000).with_utterance(16000 * 4, "late");
+        let mut source = SilentSource::new(16000 * 60);
+        let res = get_raw_utterance(&mut recognizer, &mut source, &listen_config);
+        assert!(matches!(res, Err(AssistantRsError::ListenTimeout(_))));
+
+        // Somebody never stopped speaking.
+        let script = (1..20).map(|n| (16000 * n, "la ".repeat(n))).collect();
+        let mut recognizer = ScriptedRecognizer::new(16000).with_script(script);
+        let mut source = SilentSource::new(16000 * 60);
+        let msg = get_raw_utterance(&mut recognizer, &mut source, &listen_config).unwrap();
+        assert_eq!(msg, "la ".repeat(6));
+    }
+
+    #[test]
+    fn test_vad_utterance() {
+        let listen_config = ListenConfig {
+            vad: Some(true),
+            start_timeout_ms: Some(5000),
+            ..ListenConfig::default()
+        };
+        let silence = vec![0; 16000];
+        let burst = tone(16000);
+
+        // The first burst is only noise to the recognizer, so it keeps listening for the second.
+        let mut recognizer = ScriptedRecognizer::new(16000)
+            .with_script(Vec::new())
+            .with_utterance(0, "fire fox");
+        let samples = [&silence[..], &burst, &silence, &burst, &silence].concat();
+        let mut source = SampleSource::new(samples);
+        let msg = get_raw_utterance(&mut recognizer, &mut source, &listen_config).unwrap();
+        assert_eq!(msg, "fire fox");
+
+        let mut recognizer = ScriptedRecognizer::new(16000).with_utterance(0, "fire fox");
+        let mut source = SampleSource::new(vec![0; 16000 * 10]);
+        let res = get_raw_utterance(&mut recognizer, &mut source, &listen_config);
+        assert!(matches!(res, Err(AssistantRsError::ListenTimeout(_))));
+    }
+
+    #[test]
+    fn test_run_mode_chain() {
+        let firefox_out = temp_path("firefox");
+        let telegram_out = temp_path("telegram");
+        let raw_conf = format!(
+            r#"
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "telegram"
+            command = "touch {}"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "you tube"
+            command = "touch {}"
+            "#,
+            telegram_out.display(),
+            firefox_out.display()
+        );
+        let config: Config = toml::from_str(&raw_conf).unwrap();
+        config.modes.verify().unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "you tube");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert!(wait_for_file(&firefox_out));
+        assert!(!telegram_out.exists());
+        std::fs::remove_file(&firefox_out).unwrap();
+        assert_eq!(report.transcripts, vec!["fire fox", "you tube"]);
+        assert_eq!(report.final_mode.as_deref(), Some("firefox"));
+        assert!(report.matched);
+    }
+
+    #[test]
+    fn test_run_in_mode_and_cancel() {
+        let raw_conf = r#"
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "you tube"
+            command = "true"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "you tube")
+            .with_utterance(16000, "you tube");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), Some("firefox"))
+            .unwrap();
+        assert_eq!(report.commands, vec!["true"]);
+        assert_eq!(report.final_mode.as_deref(), Some("firefox"));
+
+        let res = ctx.run_in_mode(Some("chrome"));
+        assert!(matches!(
+            res,
+            Err(AssistantRsError::Config(ConfigError::ModeNotFound(_)))
+        ));
+
+        // A cancelled run stops listening the next time it reads audio.
+        struct CancellingSource(Arc<AtomicBool>);
+        impl AudioSource for CancellingSource {
+            fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError> {
+                self.0.store(true, Ordering::SeqCst);
+                Ok(vec![0; target])
+            }
+        }
+        let mut source = CancellingSource(ctx.cancel_flag());
+        let res = ctx.run_with_source(&mut source, None);
+        assert!(matches!(res, Err(AssistantRsError::Cancelled)));
+
+        // A cancel from before the run starts is not lost.
+        let res = ctx.run_with_source(&mut SilentSource::new(16000 * 60), None);
+        assert!(matches!(res, Err(AssistantRsError::Cancelled)));
+        ctx.cancel_flag().store(false, Ordering::SeqCst);
+        let res = ctx.run_with_source(&mut SilentSource::new(16000 * 60), Some("firefox"));
+        assert!(res.is_ok());
+    }
     #[test]
     fn test_command_match() {
         let tree = ModeTree::empty();
@@ -286,8 +1400,841 @@ mod tests {
         ];
 
         let tree = tree.with_mode("youtube".to_owned(), youtube).unwrap();
-        let (to_run, next_mode) = match_commands(&tree, None, "firefox youtube");
-        assert_eq!(to_run, Vec::<String>::new());
-        assert_eq!(next_mode.as_ref().map(|s| s.as_ref()), Some("youtube"));
+        let matched = match_commands(&tree, None, "firefox youtube").unwrap();
+        assert_eq!(matched.command_lines(), Vec::<String>::new());
+        assert_eq!(matched.next_mode.as_deref(), Some("youtube"));
+    }
+
+    #[test]
+    fn test_command_match_homophones() {
+        let root = vec![
+            Command::new(
+                CommandMessage::from_raw("four".to_owned()).unwrap(),
+                Some("echo four".to_owned()),
+                None,
+            ),
+            Command::new(
+                CommandMessage::from_raw("fire".to_owned()).unwrap(),
+                Some("echo fire".to_owned()),
+                None,
+            ),
+        ];
+        let tree = ModeTree::empty().with_commands(root).unwrap();
+
+        // "fore" is closer to "fire" by spelling, but sounds exactly like "four".
+        let matched = match_commands(&tree, None, "fore").unwrap();
+        assert_eq!(matched.command_lines(), vec!["echo four"]);
+        assert_eq!(matched.next_mode, None);
+    }
+
+    #[test]
+    fn test_command_match_pronunciations() {
+        let dict_path = temp_path("pronunciations.dict");
+        std::fs::write(
+            &dict_path,
+            ";;; Extra words\nTOOB  T UW1 B\nFYRFOX  F AA1 K S\nFYRFOX(2)  F AY1 ER0\n",
+        )
+        .unwrap();
+        let raw_conf = format!(
+            r#"
+            match-threshold = 0.0
+            pronunciation-dict = "{}"
+
+            [pronunciations]
+            fyrfox = "F AY1 ER0 F AA2 K S"
+
+            [[command]]
+            message = "fyrfox"
+            command = "firefox"
+
+            [[command]]
+            message = "toob"
+            command = "youtube"
+            "#,
+            dict_path.display()
+        );
+        let mut config: Config = toml::from_str(&raw_conf).unwrap();
+        assert!(match_commands(&config.modes, None, "firefox").is_none());
+
+        // The table's pronounciation is used over the dictionary's, and transcripts use them too.
+        config.modes.load_pronunciations().unwrap();
+        let tree = &config.modes;
+        let matched = match_commands(tree, None, "firefox").unwrap();
+        assert_eq!(matched.command_lines(), vec!["firefox"]);
+        let matched = match_commands(tree, None, "tube").unwrap();
+        assert_eq!(matched.command_lines(), vec!["youtube"]);
+        let matched = match_commands(tree, None, "toob").unwrap();
+        assert_eq!(matched.command_lines(), vec!["youtube"]);
+        std::fs::remove_file(&dict_path).unwrap();
+
+        let raw_conf = r#"
+            [pronunciations]
+            fyrfox = "F IRE FOX"
+        "#;
+        let mut config: Config = toml::from_str(raw_conf).unwrap();
+        assert!(matches!(
+            config.modes.load_pronunciations(),
+            Err(ConfigError::InvalidPronunciation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_command_match_threshold() {
+        let raw_conf = r#"
+            match-threshold = 0.3
+
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "telegram"
+            command = "telegram"
+
+            [[mode]]
+            name = "firefox"
+            match-threshold = 0.0
+
+            [[mode.command]]
+            message = "you tube"
+            command = "firefox youtube.com"
+        "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let tree = &config.modes;
+        tree.verify().unwrap();
+
+        let matched = match_commands(tree, None, "firefox youtube").unwrap();
+        assert_eq!(matched.command_lines(), vec!["firefox youtube.com"]);
+        assert!(match_commands(tree, None, "what time is it").is_none());
+        assert!(match_commands(tree, None, "").is_none());
+
+        // The firefox mode only accepts exact matches.
+        assert!(match_commands(tree, Some("firefox"), "you tube").is_some());
+        assert!(match_commands(tree, Some("firefox"), "you tub").is_none());
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_config() {
+        let raw_conf = r#"
+            [[command]]
+            message = "fire fox"
+            command = "firefox"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000);
+        let mut ctx = AssistantContext::new(config.clone(), Box::new(recognizer));
+
+        // A config with a dangling mode reference parses, but does not verify.
+        let conf_path = temp_path("bad-config.toml");
+        let bad_conf = r#"
+            model-path = "model.pbmm"
+
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+            "#;
+        std::fs::write(&conf_path, bad_conf).unwrap();
+        ctx.config_paths = vec![conf_path.clone()];
+        assert!(matches!(
+            ctx.reload(),
+            Err(AssistantRsError::Config(ConfigError::ModeNotFound(_)))
+        ));
+        assert_eq!(ctx.config, config);
+
+        std::fs::write(&conf_path, "this is not toml").unwrap();
+        assert!(ctx.reload().is_err());
+        assert_eq!(ctx.config, config);
+        std::fs::remove_file(&conf_path).unwrap();
+    }
+
+    #[test]
+    fn test_run_text() {
+        // No model path is needed to only read text.
+        let conf_path = temp_path("text-config.toml");
+        let raw_conf = r#"
+            match-threshold = 0.3
+
+            [[command]]
+            message = "fire fox"
+            command = "true"
+            mode = "firefox"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "new window"
+            command = "echo {site}"
+            aliases = ["open {site}"]
+            "#;
+        std::fs::write(&conf_path, raw_conf).unwrap();
+        let mut ctx = AssistantContext::init_text_from_paths(vec![conf_path.clone()]).unwrap();
+        assert!(ctx.recognizer.is_none());
+        assert!(matches!(ctx.run(), Err(AssistantRsError::ModelNotLoaded)));
+
+        let mut lines = vec!["fire fox", "open reddit", "fire fox"]
+            .into_iter()
+            .map(|s| s.to_owned());
+        let report = ctx.run_with_text(&mut lines, None).unwrap();
+        assert_eq!(report.transcripts, vec!["fire fox", "open reddit"]);
+        assert_eq!(report.commands, vec!["true", "echo 'reddit'"]);
+        assert_eq!(report.final_mode.as_deref(), Some("firefox"));
+
+        // Running out of lines ends the run, even part way through a mode.
+        let report = ctx.run_with_text(&mut lines, None).unwrap();
+        assert_eq!(report.commands, vec!["true"]);
+        let report = ctx
+            .run_with_text(&mut std::iter::once("what time is it".to_owned()), None)
+            .unwrap();
+        assert!(!report.matched);
+        assert!(ctx
+            .run_with_text(&mut std::iter::empty(), Some("chrome"))
+            .is_err());
+
+        ctx.reload().unwrap();
+        assert!(ctx.recognizer.is_none());
+        std::fs::remove_file(&conf_path).unwrap();
+    }
+
+    #[test]
+    fn test_explain_match() {
+        let raw_conf = r#"
+            match-threshold = 0.3
+
+            [[command]]
+            message = "fire fox"
+            command = "firefox"
+            mode = "firefox"
+
+            [[command]]
+            message = "terminal"
+            command = "alacritty"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "you tube"
+            aliases = ["open {site}"]
+            command = ["firefox", "{site}.com"]
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let explained = explain_match(&config.modes, None, "fire fox open reddit");
+        assert!(explained.matched);
+        assert_eq!(explained.steps.len(), 2);
+        assert_eq!(explained.steps[0].mode, None);
+        assert_eq!(explained.steps[0].chosen.as_deref(), Some("fire fox"));
+        let messages: Vec<_> = explained.steps[1]
+            .candidates
+            .iter()
+            .map(|c| c.message.as_str())
+            .collect();
+        assert_eq!(messages, vec!["you tube", "open {site}"]);
+        assert_eq!(explained.steps[1].candidates[1].distance, Some(0));
+        assert_eq!(explained.steps[1].chosen.as_deref(), Some("open {site}"));
+        assert_eq!(explained.commands, vec!["firefox", "firefox reddit.com"]);
+        assert_eq!(explained.final_mode, None);
+
+        // The same match is found as when running, even if it is rejected.
+        let explained = explain_match(&config.modes, None, "what time is it");
+        assert!(!explained.matched);
+        assert!(explained.distance.unwrap() > 0.3);
+        assert!(match_commands(&config.modes, None, "what time is it").is_none());
+        let text = explained.to_string();
+        assert!(text.contains("No command matched."));
+
+        let json = serde_json::to_value(explain_match(&config.modes, Some("firefox"), "you tube"))
+            .unwrap();
+        assert_eq!(json["start-mode"], "firefox");
+        assert_eq!(json["steps"][0]["chosen"], "you tube");
+        assert_eq!(json["commands"][0], "firefox {site}.com");
+    }
+
+    #[test]
+    fn test_command_match_aliases() {
+        let raw_conf = r#"
+            match-threshold = 0.2
+
+            [[command]]
+            message = "fire fox"
+            aliases = ["open fire fox", "you tube"]
+            command = "firefox"
+
+            [[command]]
+            message = "terminal"
+            command = "alacritty"
+        "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let tree = &config.modes;
+        tree.verify().unwrap();
+        for text in &["fire fox", "open firefox", "you tube"] {
+            let matched = match_commands(tree, None, text).unwrap();
+            assert_eq!(matched.command_lines(), vec!["firefox"]);
+        }
+        let matched = match_commands(tree, None, "terminal").unwrap();
+        assert_eq!(matched.command_lines(), vec!["alacritty"]);
+
+        // Aliases count as messages when looking for duplicates.
+        let dup_alias = Command::new(
+            CommandMessage::from_raw("browser".to_owned()).unwrap(),
+            Some("chromium".to_owned()),
+            None,
+        )
+        .with_aliases(vec![
+            CommandMessage::from_raw("you tube".to_owned()).unwrap()
+        ]);
+        let res = config.modes.clone().with_commands(vec![dup_alias]);
+        assert!(matches!(res, Err(ConfigError::DuplicateMessage(_))));
+
+        let raw_conf = r#"
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "you tube"
+            aliases = ["you tube"]
+            command = "firefox youtube.com"
+        "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        assert!(matches!(
+            config.modes.verify(),
+            Err(ConfigError::DuplicateMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_match_global() {
+        let raw_conf = r#"
+            [[global-command]]
+            message = "cancel"
+
+            [[global-command]]
+            message = "stop listening"
+            command = "pkill assistant-rs"
+
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "terminal"
+            mode = "terminal"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "you tube"
+            command = "firefox youtube.com"
+
+            [[mode]]
+            name = "terminal"
+            inherit-global = false
+
+            [[mode.command]]
+            message = "top"
+            command = "alacritty -e top"
+        "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let tree = &config.modes;
+        tree.verify().unwrap();
+
+        let matched = match_commands(tree, None, "stop listening").unwrap();
+        assert_eq!(matched.command_lines(), vec!["pkill assistant-rs"]);
+
+        let matched = match_commands(tree, Some("firefox"), "cancel").unwrap();
+        assert_eq!(matched.command_lines(), Vec::<&str>::new());
+        assert_eq!(matched.next_mode, None);
+        let matched = match_commands(tree, None, "fire fox stop listening").unwrap();
+        assert_eq!(matched.command_lines(), vec!["pkill assistant-rs"]);
+
+        // The terminal mode opted out of the global commands.
+        let matched = match_commands(tree, Some("terminal"), "stop listening").unwrap();
+        assert_eq!(matched.command_lines(), vec!["alacritty -e top"]);
+
+        // Global commands may not clash with any mode's own messages.
+        let clash = Command::new(
+            CommandMessage::from_raw("you tube".to_owned()).unwrap(),
+            None,
+            None,
+        );
+        let tree = tree.clone().with_global_commands(vec![clash]).unwrap();
+        assert!(matches!(
+            tree.verify(),
+            Err(ConfigError::DuplicateMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_match_slots() {
+        let raw_conf = r#"
+            match-threshold = 0.3
+
+            [[command]]
+            message = "volume {level:number}"
+            command = "amixer set Master {level}%"
+
+            [[command]]
+            message = "open {site:word}"
+            mode = "browser"
+
+            [[mode]]
+            name = "browser"
+
+            [[mode.command]]
+            message = "new window"
+            command = "firefox --new-window {site}.com"
+        "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let tree = &config.modes;
+
+        let matched = match_commands(tree, None, "volume twenty five").unwrap();
+        assert_eq!(matched.command_lines(), vec!["amixer set Master {level}%"]);
+        assert_eq!(matched.slots["level"], "25");
+
+        let matched = match_commands(tree, None, "open reddit new window").unwrap();
+        assert_eq!(matched.slots["site"], "reddit");
+        assert_eq!(
+            slots::substitute(&matched.command_lines()[0], &matched.slots),
+            "firefox --new-window reddit.com"
+        );
+
+        // Slots that cannot be filled never match.
+        assert!(match_commands(tree, None, "volume").is_none());
+    }
+
+    #[test]
+    fn test_run_slots() {
+        let slot_out = temp_path("slot");
+        let raw_conf = format!(
+            r#"
+            [[command]]
+            message = "volume {{level:number}}"
+            command = "echo {{level}} $ASSISTANT_SLOT_LEVEL > {}"
+            "#,
+            slot_out.display()
+        );
+        let config: Config = toml::from_str(&raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000).with_utterance(16000, "volume thirty");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(report.slots["level"], "30");
+        assert!(wait_for_file(&slot_out));
+        // The file may exist before the shell finishes writing it.
+        let start = Instant::now();
+        let mut contents = String::new();
+        while contents.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            contents = std::fs::read_to_string(&slot_out).unwrap();
+        }
+        assert_eq!(contents, "30 30\n");
+        std::fs::remove_file(&slot_out).unwrap();
+    }
+
+    #[test]
+    fn test_run_back_after_multiple_modes() {
+        let raw_conf = r#"
+            [[global-command]]
+            message = "back"
+            action = "back"
+
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "you tube"
+            mode = "youtube"
+
+            [[mode.command]]
+            message = "new window"
+            command = "true new window"
+
+            [[mode]]
+            name = "youtube"
+
+            [[mode.command]]
+            message = "stop"
+            command = "true stop"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        config.modes.verify().unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox you tube")
+            .with_utterance(16000, "back")
+            .with_utterance(16000, "new window");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+
+        // Going back from a mode entered in the same utterance as its parent returns to the parent.
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(
+            report.transcripts,
+            vec!["fire fox you tube", "back", "new window"]
+        );
+        assert_eq!(report.commands, vec!["true new window"]);
+        assert_eq!(report.final_mode.as_deref(), Some("firefox"));
+    }
+
+    #[test]
+    fn test_run_actions() {
+        let raw_conf = r#"
+            [[global-command]]
+            message = "back"
+            action = "back"
+
+            [[global-command]]
+            message = "go"
+            action = "repeat-last"
+
+            [[global-command]]
+            message = "stop"
+            action = "exit-daemon"
+
+            [[global-command]]
+            message = "cancel"
+            action = "cancel"
+
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "terminal"
+            command = "true"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "new window"
+            command = "true new window"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        config.modes.verify().unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "back")
+            .with_utterance(16000, "terminal")
+            .with_utterance(16000, "go")
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "stop")
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "cancel");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let run = |ctx: &mut AssistantContext| {
+            ctx.run_with_source(&mut SilentSource::new(16000 * 60), None)
+                .unwrap()
+        };
+
+        // Going back from the firefox mode listens in the root mode again.
+        let report = run(&mut ctx);
+        assert_eq!(report.transcripts, vec!["fire fox", "back", "terminal"]);
+        assert_eq!(report.commands, vec!["true"]);
+        assert_eq!(report.actions, vec![Action::Back]);
+        assert_eq!(report.final_mode, None);
+
+        let report = run(&mut ctx);
+        assert_eq!(report.commands, vec!["true"]);
+        assert_eq!(report.actions, vec![Action::RepeatLast]);
+
+        let report = run(&mut ctx);
+        assert_eq!(report.actions, vec![Action::ExitDaemon]);
+        assert_eq!(report.final_mode.as_deref(), Some("firefox"));
+
+        let report = run(&mut ctx);
+        assert_eq!(report.commands, Vec::<String>::new());
+        assert_eq!(report.actions, vec![Action::Cancel]);
+    }
+
+    #[test]
+    fn test_run_mode_timeout() {
+        let raw_conf = r#"
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "terminal"
+            command = "true"
+
+            [[mode]]
+            name = "firefox"
+            timeout-ms = 2000
+            on-timeout = "root"
+
+            [[mode.command]]
+            message = "you tube"
+            mode = "youtube"
+
+            [[mode]]
+            name = "youtube"
+            timeout-ms = 2000
+
+            [[mode.command]]
+            message = "new window"
+            command = "true new window"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        config.modes.verify().unwrap();
+
+        // The second utterance starts too late, so the run returns to the root mode for the third.
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000 * 5, "you tube")
+            .with_utterance(16000, "terminal")
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "you tube")
+            .with_utterance(16000 * 5, "new window");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(report.transcripts, vec!["fire fox", "terminal"]);
+        assert_eq!(report.commands, vec!["true"]);
+        assert_eq!(report.final_mode, None);
+        assert!(report.timed_out);
+
+        // The youtube mode ends the run instead.
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(report.transcripts, vec!["fire fox", "you tube"]);
+        assert_eq!(report.commands, Vec::<String>::new());
+        assert_eq!(report.final_mode.as_deref(), Some("youtube"));
+        assert!(report.timed_out);
+    }
+
+    #[test]
+    fn test_run_exec_policy() {
+        let raw_conf = r#"
+            [[command]]
+            message = "volume {level:number}"
+            command = ["sh", "-c", "echo $0 $VOLUME_STEP; exit 4", "{level}"]
+            capture-output = true
+            env = { VOLUME_STEP = "5" }
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        config.modes.verify().unwrap();
+        let recognizer = ScriptedRecognizer::new(16000).with_utterance(16000, "volume thirty");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(
+            report.commands,
+            vec!["sh -c 'echo $0 $VOLUME_STEP; exit 4' 30"]
+        );
+        let status = &report.command_statuses[0];
+        assert_eq!(status.exit_code, Some(4));
+        assert_eq!(status.stdout.as_deref(), Some("30 5\n"));
+
+        let raw_conf = r#"
+            [[command]]
+            message = "fire fox"
+            command = []
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        assert!(matches!(
+            config.modes.verify(),
+            Err(ConfigError::EmptyCommand(_))
+        ));
+    }
+
+    #[test]
+    fn test_run_command_env() {
+        let raw_conf = r#"
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "new window"
+            aliases = ["open {site}"]
+            command = "echo \"$ASSISTANT_TRANSCRIPT|$ASSISTANT_MATCHED_MESSAGE|$ASSISTANT_MODE_PATH|$ASSISTANT_SLOT_SITE\""
+            capture-output = true
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "open reddit");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(
+            report.command_statuses[0].stdout.as_deref(),
+            Some("open reddit|open {site}|firefox|reddit\n")
+        );
+
+        let matched = match_commands(&ctx.config.modes, None, "fire fox new window").unwrap();
+        let env = command_env("fire fox new window", &[], Some(&matched));
+        assert_eq!(env["ASSISTANT_MATCHED_MESSAGE"], "fire fox new window");
+        assert_eq!(env["ASSISTANT_MODE_PATH"], "firefox");
+        assert_eq!(env["ASSISTANT_MATCH_DISTANCE"], "0");
+        assert!(!env.contains_key("ASSISTANT_SLOT_SITE"));
+
+        // The mode a message switches into is not part of the path it was heard in.
+        let matched = match_commands(&ctx.config.modes, None, "fire fox").unwrap();
+        let env = command_env("fire fox", &[], Some(&matched));
+        assert_eq!(env["ASSISTANT_MODE_PATH"], "");
+    }
+
+    #[test]
+    fn test_run_say() {
+        let raw_conf = r#"
+            [[global-command]]
+            message = "back"
+            action = "back"
+
+            [[global-command]]
+            message = "stop"
+            action = "cancel"
+            say = "never mind"
+
+            [[command]]
+            message = "fire fox"
+            command = "true"
+            say = "opening firefox"
+            mode = "firefox"
+
+            [[command]]
+            message = "what time is it"
+            command = "echo it is noon"
+            say-output = true
+
+            [[mode]]
+            name = "firefox"
+            say = "firefox: which page?"
+
+            [[mode.command]]
+            message = "open {site}"
+            say = "opening {site}"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "back")
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "open reddit")
+            .with_utterance(16000, "what time is it")
+            .with_utterance(16000, "fire fox stop");
+        let tts = RecordingTtsSink::default();
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer))
+            .with_tts_sink(Box::new(tts.clone()));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        let expected = vec![
+            "opening firefox",
+            "firefox: which page?",
+            "opening firefox",
+            "firefox: which page?",
+            "opening reddit",
+        ];
+        assert_eq!(tts.said(), expected);
+        assert_eq!(report.spoken, expected);
+
+        // Captured output is spoken, and its command still reported.
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(report.spoken, vec!["it is noon"]);
+        assert_eq!(report.commands, vec!["echo it is noon"]);
+
+        // Only the cancelling command speaks along a cancelled path.
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(report.spoken, vec!["never mind"]);
+        assert!(report.commands.is_empty());
+    }
+
+    #[test]
+    fn test_run_cues() {
+        let raw_conf = r#"
+            match-threshold = 0.3
+
+            [[global-command]]
+            message = "back"
+            action = "back"
+
+            [[command]]
+            message = "fire fox"
+            mode = "firefox"
+
+            [[command]]
+            message = "terminal"
+            command = "true"
+
+            [[mode]]
+            name = "firefox"
+
+            [[mode.command]]
+            message = "new window"
+            command = "true"
+            "#;
+        let config: Config = toml::from_str(raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000)
+            .with_utterance(16000, "fire fox")
+            .with_utterance(16000, "back")
+            .with_utterance(16000, "terminal")
+            .with_utterance(16000, "what time is it");
+        let cues = RecordingCuePlayer::default();
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer))
+            .with_cue_player(Box::new(cues.clone()));
+        ctx.run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(
+            cues.played(),
+            vec![
+                Cue::Listening,
+                Cue::ModeEntered,
+                Cue::ModeEntered,
+                Cue::Matched
+            ]
+        );
+
+        ctx.run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert_eq!(cues.played()[4..], [Cue::Listening, Cue::NoMatch]);
+    }
+
+    #[test]
+    fn test_run_no_match() {
+        let no_match_out = temp_path("no-match");
+        let raw_conf = format!(
+            r#"
+            match-threshold = 0.3
+            on-no-match = "touch {}"
+
+            [[command]]
+            message = "fire fox"
+            command = "firefox"
+            "#,
+            no_match_out.display()
+        );
+        let config: Config = toml::from_str(&raw_conf).unwrap();
+        let recognizer = ScriptedRecognizer::new(16000).with_utterance(16000, "new window");
+        let mut ctx = AssistantContext::new(config, Box::new(recognizer));
+        let report = ctx
+            .run_with_source(&mut SilentSource::new(16000 * 60), None)
+            .unwrap();
+        assert!(wait_for_file(&no_match_out));
+        std::fs::remove_file(&no_match_out).unwrap();
+        assert!(!report.matched);
+        assert_eq!(report.transcripts, vec!["new window"]);
     }
 }
@@ -0,0 +1,334 @@
+use crate::context::RunReport;
+use crate::error::{error_message, AssistantRsError};
+
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A request sent to the daemon over the control socket, as a single line of JSON.
+///
+/// For example, `{"request": "listen-in-mode", "mode": "firefox"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    /// Listen and run a single command.
+    Listen,
+
+    /// Listen and run a single command, starting in the named mode.
+    ListenInMode { mode: String },
+
+    /// Reload the assistant's configuration from the config files.
+    Reload,
+
+    /// Report what the daemon is currently doing.
+    Status,
+
+    /// Stop the current listen without running anything.
+    Cancel,
+
+    /// Stop the daemon.
+    Shutdown,
+}
+
+/// The daemon's reply to a `ControlRequest`, sent back as a single line of JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "reply", rename_all = "kebab-case")]
+pub enum ControlReply {
+    /// The result of a `listen` or `listen-in-mode` request.
+    Run(RunReport),
+
+    /// The result of a `status` request.
+    Status(DaemonStatus),
+
+    /// The request was carried out.
+    Ok,
+
+    /// The request failed.
+    Error { message: String },
+}
+
+impl From<&AssistantRsError> for ControlReply {
+    fn from(err: &AssistantRsError) -> Self {
+        ControlReply::Error {
+            message: error_message(err),
+        }
+    }
+}
+
+/// What the daemon is currently doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DaemonState {
+    #[default]
+    Idle,
+    Listening,
+    Reloading,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DaemonStatus {
+    pub state: DaemonState,
+
+    /// The mode the current listen started in, if it did not start in the root mode.
+    pub mode: Option<String>,
+}
+
+/// A request for the daemon's main thread to carry out, along with where to send the reply.
+///
+/// Requests triggered by signals have nobody to reply to.
+pub struct DaemonJob {
+    pub request: ControlRequest,
+    pub reply: Option<crossbeam::Sender<ControlReply>>,
+}
+
+/// The state shared between the daemon's main thread and the control socket's connections.
+#[derive(Clone)]
+pub struct DaemonHandle {
+    pub jobs: crossbeam::Sender<DaemonJob>,
+    pub status: Arc<Mutex<DaemonStatus>>,
+    pub cancel: Arc<AtomicBool>,
+
+    /// Set once the daemon has been asked to shut down, so that `cancel` stays set for any listen
+    /// still queued ahead of the shutdown.
+    pub shutting_down: Arc<AtomicBool>,
+}
+
+impl DaemonHandle {
+    /// Stops any listen in progress or still to come, so that the shutdown does not wait on the speaker.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// Answers a request from a client, forwarding it to the main thread if needed.
+    ///
+    /// `status` and `cancel` are answered immediately, even while the main thread is busy listening.
+    fn handle(&self, request: ControlRequest) -> ControlReply {
+        match request {
+            ControlRequest::Status => {
+                let status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+                ControlReply::Status(status.clone())
+            }
+            ControlRequest::Cancel => {
+                let status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+                if status.state == DaemonState::Listening {
+                    self.cancel.store(true, Ordering::SeqCst);
+                    ControlReply::Ok
+                } else {
+                    ControlReply::Error {
+                        message: "the assistant is not listening".to_owned(),
+                    }
+                }
+            }
+            request => {
+                if request == ControlRequest::Shutdown {
+                    self.begin_shutdown();
+                }
+                let (reply_send, reply_recv) = crossbeam::bounded(1);
+                let job = DaemonJob {
+                    request,
+                    reply: Some(reply_send),
+                };
+                if self.jobs.send(job).is_err() {
+                    return ControlReply::Error {
+                        message: "the daemon is shutting down".to_owned(),
+                    };
+                }
+                reply_recv.recv().unwrap_or_else(|_| ControlReply::Error {
+                    message: "the daemon is shutting down".to_owned(),
+                })
+            }
+        }
+    }
+}
+
+/// Listens for clients on a Unix domain socket, removing the socket file when dropped.
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Binds the socket at `path` and starts accepting clients in the background.
+    ///
+    /// A stale socket left behind by a previous daemon is replaced, but one that another daemon
+    /// is still listening on is not.
+    pub fn start(path: &Path, handle: DaemonHandle) -> Result<Self, AssistantRsError> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                let err = std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is already in use", path.display()),
+                );
+                return Err(AssistantRsError::ControlSocket(err));
+            }
+            std::fs::remove_file(path).map_err(AssistantRsError::ControlSocket)?;
+        }
+        let listener = UnixListener::bind(path).map_err(AssistantRsError::ControlSocket)?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let handle = handle.clone();
+                        std::thread::spawn(move || serve_client(stream, handle));
+                    }
+                    Err(e) => {
+                        log::log!(log::Level::Warn, "Error accepting control client: {}", e);
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Answers each line a client sends until it disconnects.
+fn serve_client(stream: UnixStream, handle: DaemonHandle) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            log::log!(log::Level::Warn, "Error opening control client: {}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        log::log!(log::Level::Debug, "Control request: {}", line);
+        let reply = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => handle.handle(request),
+            Err(e) => ControlReply::Error {
+                message: format!("invalid request: {}", e),
+            },
+        };
+        let mut raw_reply = serde_json::to_string(&reply).unwrap_or_default();
+        raw_reply.push('\n');
+        if writer.write_all(raw_reply.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ConfigError;
+    use crate::testing::temp_path;
+
+    fn send_request(path: &Path, line: &str) -> String {
+        let mut stream = UnixStream::connect(path).unwrap();
+        writeln!(stream, "{}", line).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_protocol_format() {
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"request": "listen-in-mode", "mode": "firefox"}"#).unwrap();
+        assert_eq!(
+            request,
+            ControlRequest::ListenInMode {
+                mode: "firefox".to_owned()
+            }
+        );
+        let request: ControlRequest = serde_json::from_str(r#"{"request": "status"}"#).unwrap();
+        assert_eq!(request, ControlRequest::Status);
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"request": "dance"}"#).is_err());
+
+        let report = RunReport {
+            transcripts: vec!["fire fox".to_owned()],
+            commands: vec!["firefox".to_owned()],
+            command_statuses: Vec::new(),
+            final_mode: None,
+            matched: true,
+            slots: Default::default(),
+            actions: Vec::new(),
+            timed_out: false,
+            spoken: Vec::new(),
+        };
+        assert_eq!(
+            serde_json::to_string(&ControlReply::Run(report)).unwrap(),
+            r#"{"reply":"run","transcripts":["fire fox"],"commands":["firefox"],"command-statuses":[],"final-mode":null,"matched":true,"slots":{},"actions":[],"timed-out":false,"spoken":[]}"#
+        );
+        let err = AssistantRsError::from(ConfigError::EmptyMode("firefox".to_owned()));
+        assert_eq!(
+            serde_json::to_string(&ControlReply::from(&err)).unwrap(),
+            r#"{"reply":"error","message":"config error: mode firefox is empty"}"#
+        );
+    }
+
+    #[test]
+    fn test_control_server() {
+        let path = temp_path("control.sock");
+        let (jobs, job_recv) = crossbeam::unbounded();
+        let handle = DaemonHandle {
+            jobs,
+            status: Arc::default(),
+            cancel: Arc::default(),
+            shutting_down: Arc::default(),
+        };
+        let server = ControlServer::start(&path, handle.clone()).unwrap();
+        assert!(ControlServer::start(&path, handle.clone()).is_err());
+
+        // Stand in for the main thread, which is busy listening until it is cancelled.
+        let status = Arc::clone(&handle.status);
+        let cancel = Arc::clone(&handle.cancel);
+        let shutting_down = Arc::clone(&handle.shutting_down);
+        let main_thread = std::thread::spawn(move || {
+            let job: DaemonJob = job_recv.recv().unwrap();
+            assert_eq!(job.request, ControlRequest::Listen);
+            status.lock().unwrap().state = DaemonState::Listening;
+            while !cancel.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            status.lock().unwrap().state = DaemonState::Idle;
+            cancel.store(false, Ordering::SeqCst);
+            let reply = ControlReply::from(&AssistantRsError::Cancelled);
+            job.reply.unwrap().send(reply).unwrap();
+
+            // A shutdown cancels any listen that is still to come, before it reaches the main thread.
+            let job: DaemonJob = job_recv.recv().unwrap();
+            assert_eq!(job.request, ControlRequest::Shutdown);
+            assert!(shutting_down.load(Ordering::SeqCst));
+            assert!(cancel.load(Ordering::SeqCst));
+            job.reply.unwrap().send(ControlReply::Ok).unwrap();
+        });
+
+        let request = |line: &str| send_request(&path, line);
+        let listener = {
+            let path = path.clone();
+            std::thread::spawn(move || send_request(&path, r#"{"request": "listen"}"#))
+        };
+        while !request(r#"{"request": "status"}"#).contains("listening") {
+            std::thread::yield_now();
+        }
+        assert_eq!(request(r#"{"request": "cancel"}"#), "{\"reply\":\"ok\"}\n");
+        assert_eq!(
+            listener.join().unwrap(),
+            "{\"reply\":\"error\",\"message\":\"listening was cancelled\"}\n"
+        );
+        assert!(request("not json").starts_with(r#"{"reply":"error""#));
+        assert_eq!(request(r#"{"request": "shutdown"}"#), "{\"reply\":\"ok\"}\n");
+        main_thread.join().unwrap();
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
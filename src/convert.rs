@@ -0,0 +1,161 @@
+use cpal::Sample;
+
+/// Converts interleaved audio in any sample format, channel count, and sample rate into the mono,
+/// 16-bit audio at the model's sample rate that the recognizer expects.
+pub struct FormatConverter {
+    channels: usize,
+    resampler: Resampler,
+}
+
+impl FormatConverter {
+    pub fn new(channels: u16, from_rate: u32, to_rate: u32) -> Self {
+        Self {
+            channels: usize::from(channels.max(1)),
+            resampler: Resampler::new(from_rate, to_rate),
+        }
+    }
+
+    /// Converts a block of interleaved samples.
+    ///
+    /// Any incomplete frame at the end of `data` is dropped.
+    pub fn convert<T: Sample>(&mut self, data: &[T]) -> Vec<i16> {
+        let mono: Vec<i16> = data
+            .chunks_exact(self.channels)
+            .map(|frame| {
+                let total: i32 = frame.iter().map(|s| i32::from(s.to_i16())).sum();
+                (total / frame.len() as i32) as i16
+            })
+            .collect();
+        self.resampler.process(&mono)
+    }
+}
+
+/// A streaming linear-interpolation resampler.
+///
+/// When downsampling, the audio is low-pass filtered first to reduce aliasing.
+pub struct Resampler {
+    /// The number of input samples per output sample.
+    step: f64,
+
+    /// The position of the next output sample, in input samples since the start of the next block.
+    /// A value of `-1.0` is the last sample of the previous block.
+    position: f64,
+    previous: Option<f64>,
+
+    /// The smoothing factor of the low-pass filter, or `None` if no filtering is needed.
+    filter_alpha: Option<f64>,
+    filter_state: [f64; 2],
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        let step = f64::from(from_rate) / f64::from(to_rate.max(1));
+        let filter_alpha = if from_rate > to_rate {
+            // Two cascaded one-pole filters with their cutoff at the output's Nyquist frequency.
+            let cutoff = f64::from(to_rate) / 2.0;
+            let exponent = -2.0 * std::f64::consts::PI * cutoff / f64::from(from_rate);
+            Some(1.0 - exponent.exp())
+        } else {
+            None
+        };
+        Self {
+            step,
+            position: 0.0,
+            previous: None,
+            filter_alpha,
+            filter_state: [0.0; 2],
+        }
+    }
+
+    /// Resamples the next block of audio.
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        if input.is_empty() {
+            return Vec::new();
+        }
+        let filtered = self.filter(input);
+        let previous = self.previous.unwrap_or(filtered[0]);
+        let sample_at = |idx: isize| {
+            if idx < 0 {
+                previous
+            } else {
+                filtered[idx as usize]
+            }
+        };
+
+        let last_idx = (filtered.len() - 1) as f64;
+        let mut retvl = Vec::with_capacity((filtered.len() as f64 / self.step) as usize + 1);
+        while self.position <= last_idx {
+            let base = self.position.floor();
+            let frac = self.position - base;
+            let lower = sample_at(base as isize);
+            let value = if frac > 0.0 {
+                lower + (sample_at(base as isize + 1) - lower) * frac
+            } else {
+                lower
+            };
+            retvl.push(value.round().clamp(-32768.0, 32767.0) as i16);
+            self.position += self.step;
+        }
+        self.position -= filtered.len() as f64;
+        self.previous = filtered.last().copied();
+        retvl
+    }
+
+    fn filter(&mut self, input: &[i16]) -> Vec<f64> {
+        let alpha = match self.filter_alpha {
+            Some(alpha) => alpha,
+            None => return input.iter().map(|&s| f64::from(s)).collect(),
+        };
+        if self.previous.is_none() {
+            // Start the filter at the first sample so it does not ramp up from silence.
+            self.filter_state = [f64::from(input[0]); 2];
+        }
+        input
+            .iter()
+            .map(|&s| {
+                self.filter_state[0] += alpha * (f64::from(s) - self.filter_state[0]);
+                self.filter_state[1] += alpha * (self.filter_state[0] - self.filter_state[1]);
+                self.filter_state[1]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_lengths() {
+        let input = vec![1000i16; 4800];
+        let mut down = Resampler::new(48000, 16000);
+        let out: Vec<i16> = input.chunks(441).flat_map(|c| down.process(c)).collect();
+        assert_eq!(out.len(), 1600);
+        assert!(out.iter().all(|&s| s == 1000));
+
+        let mut up = Resampler::new(8000, 16000);
+        let out: Vec<i16> = input.chunks(100).flat_map(|c| up.process(c)).collect();
+        assert!((out.len() as isize - 9600).abs() <= 1);
+        assert!(out.iter().all(|&s| s == 1000));
+
+        let mut same = Resampler::new(16000, 16000);
+        let ramp: Vec<i16> = (0..1000).collect();
+        let out: Vec<i16> = ramp.chunks(33).flat_map(|c| same.process(c)).collect();
+        assert_eq!(out, ramp);
+    }
+
+    #[test]
+    fn test_upsample_interpolates() {
+        let mut up = Resampler::new(8000, 16000);
+        let mut out = up.process(&[0, 100]);
+        out.extend(up.process(&[200]));
+        assert_eq!(out, vec![0, 50, 100, 150, 200]);
+    }
+
+    #[test]
+    fn test_convert_downmix() {
+        let mut conv = FormatConverter::new(2, 16000, 16000);
+        let stereo: Vec<i16> = vec![100, 300, -200, -400, 7];
+        assert_eq!(conv.convert(&stereo), vec![200, -300]);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::buffer::AudioReciever;
+use crate::config::AudioConfig;
+use crate::error::{AssistantRsError, CpalError};
+use crate::utils::CpalDeviceUtils;
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{BuildStreamError, SampleFormat, SupportedStreamConfig};
+
+/// Opens the microphone to use, recording mono audio at `sample_rate`.
+///
+/// If the config names an input device, that device is used. Otherwise the host's default input
+/// device is tried first, falling back to the first device that can be opened at all.
+/// Devices that do not natively record mono, 16-bit audio at `sample_rate` are converted on the fly.
+pub fn build_audio_stream(
+    sample_rate: u32,
+    conf: &AudioConfig,
+) -> Result<AudioReciever, AssistantRsError> {
+    let hosts = candidate_hosts(conf)?;
+    if let Some(pattern) = conf.audio_device.as_deref() {
+        let devices = hosts
+            .iter()
+            .filter_map(|host| host.input_devices().ok())
+            .flatten();
+        let dev = find_by_name(devices, |dev| dev.name().ok(), pattern)
+            .ok_or_else(|| AssistantRsError::InputDeviceNotFound(pattern.to_owned()))?;
+        return open_device(&dev, sample_rate);
+    }
+
+    let dev = hosts
+        .first()
+        .and_then(|host| host.default_input_device())
+        .ok_or(AssistantRsError::MicrophoneNotFound)?;
+    let res = open_device(&dev, sample_rate);
+    let try_other = matches!(
+        res,
+        Err(AssistantRsError::Cpal(CpalError::BuildStream(
+            BuildStreamError::StreamConfigNotSupported
+        )))
+    );
+    if !try_other {
+        return res;
+    }
+
+    // If the default input cannot be opened, find one that can, preferring those that
+    // support what we need without any conversion.
+    let native_conf = cpal::StreamConfig {
+        channels: 1,
+        sample_rate: cpal::SampleRate(sample_rate),
+    };
+    let (native, converted): (Vec<_>, Vec<_>) = hosts
+        .iter()
+        .filter_map(|host| host.input_devices().ok())
+        .flatten()
+        .partition(|dev| {
+            dev.input_supports(&native_conf, SampleFormat::I16)
+                .unwrap_or(false)
+        });
+
+    // Return the first usable, or `MicrophoneNotFound` on error.
+    native
+        .iter()
+        .chain(converted.iter())
+        .find_map(|dev| open_device(dev, sample_rate).ok())
+        .ok_or(AssistantRsError::MicrophoneNotFound)
+}
+
+/// Starts recording from `dev` using the config closest to mono, 16-bit audio at `sample_rate`.
+fn open_device(dev: &cpal::Device, sample_rate: u32) -> Result<AudioReciever, AssistantRsError> {
+    let config = choose_input_config(dev, sample_rate).ok_or(CpalError::BuildStream(
+        BuildStreamError::StreamConfigNotSupported,
+    ))?;
+    AudioReciever::construct(dev, &config, sample_rate)
+}
+
+/// Picks the input config of `dev` that needs the least conversion to reach mono, 16-bit audio at `sample_rate`.
+///
+/// Matching the sample rate matters most, since resampling is the most lossy step, followed by the
+/// channel count and then the sample format.
+fn choose_input_config(dev: &cpal::Device, sample_rate: u32) -> Option<SupportedStreamConfig> {
+    let rate = cpal::SampleRate(sample_rate);
+    let format_rank = |format| match format {
+        SampleFormat::I16 => 0,
+        SampleFormat::F32 => 1,
+        SampleFormat::U16 => 2,
+    };
+    let best = dev.supported_input_configs().ok()?.min_by_key(|range| {
+        let has_rate = range.min_sample_rate() <= rate && rate <= range.max_sample_rate();
+        (
+            !has_rate,
+            range.channels(),
+            format_rank(range.sample_format()),
+        )
+    })?;
+    let config = if best.min_sample_rate() > rate {
+        // Every rate is too fast, so use the slowest.
+        let min_rate = best.min_sample_rate();
+        best.with_sample_rate(min_rate)
+    } else if best.max_sample_rate() < rate {
+        best.with_max_sample_rate()
+    } else {
+        best.with_sample_rate(rate)
+    };
+    Some(config)
+}
+
+/// Finds the speaker to play sounds on.
+///
+/// If `name` is given, the output device matching it is used. Otherwise the first host's default
+/// output device is used.
+pub fn find_output_device(
+    conf: &AudioConfig,
+    name: Option<&str>,
+) -> Result<cpal::Device, AssistantRsError> {
+    let hosts = candidate_hosts(conf)?;
+    if let Some(pattern) = name {
+        let devices = hosts
+            .iter()
+            .filter_map(|host| host.output_devices().ok())
+            .flatten();
+        return find_by_name(devices, |dev| dev.name().ok(), pattern)
+            .ok_or_else(|| AssistantRsError::OutputDeviceNotFound(pattern.to_owned()));
+    }
+    hosts
+        .first()
+        .and_then(|host| host.default_output_device())
+        .ok_or(AssistantRsError::SpeakerNotFound)
+}
+
+/// Gets the audio hosts to search for devices, in order of preference.
+///
+/// If the config names a host only that host is used; otherwise the default host comes first.
+fn candidate_hosts(conf: &AudioConfig) -> Result<Vec<cpal::Host>, AssistantRsError> {
+    let host_ids = cpal::available_hosts();
+    if let Some(pattern) = conf.audio_host.as_deref() {
+        let host = find_by_name(host_ids, |id| Some(id.name().to_owned()), pattern)
+            .and_then(|id| cpal::host_from_id(id).ok())
+            .ok_or_else(|| AssistantRsError::AudioHostNotFound(pattern.to_owned()))?;
+        return Ok(vec![host]);
+    }
+    let default_host = cpal::default_host();
+    let default_id = default_host.id();
+    let other_hosts = host_ids
+        .into_iter()
+        .filter(|id| *id != default_id)
+        .filter_map(|id| cpal::host_from_id(id).ok());
+    Ok(std::iter::once(default_host).chain(other_hosts).collect())
+}
+
+/// Finds the item whose name is `pattern`, ignoring case, or else the first whose name contains `pattern`.
+fn find_by_name<T>(
+    items: impl IntoIterator<Item = T>,
+    get_name: impl Fn(&T) -> Option<String>,
+    pattern: &str,
+) -> Option<T> {
+    let pattern = pattern.to_lowercase();
+    let mut substring_match = None;
+    for itm in items {
+        let name = match get_name(&itm) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+        if name == pattern {
+            return Some(itm);
+        }
+        if substring_match.is_none() && name.contains(&pattern) {
+            substring_match = Some(itm);
+        }
+    }
+    substring_match
+}
+
+/// Prints every audio host and input device, along with the configurations each device supports.
+pub fn list_devices() {
+    let default_id = cpal::default_host().id();
+    for id in cpal::available_hosts() {
+        let default_marker = if id == default_id { " (default)" } else { "" };
+        println!("Host: {}{}", id.name(), default_marker);
+        let host = match cpal::host_from_id(id) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("  Unavailable: {}", e);
+                continue;
+            }
+        };
+        let default_name = host.default_input_device().and_then(|dev| dev.name().ok());
+        let devices = match host.input_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                println!("  Error listing devices: {}", e);
+                continue;
+            }
+        };
+        for dev in devices {
+            let name = dev.name().unwrap_or_else(|_| "<unknown>".to_owned());
+            let default_marker = if Some(&name) == default_name.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("  Input device: \"{}\"{}", name, default_marker);
+            match dev.supported_input_configs() {
+                Ok(configs) => {
+                    for cfg in configs {
+                        println!(
+                            "    {} channel(s), {}-{} Hz, {:?}",
+                            cfg.channels(),
+                            cfg.min_sample_rate().0,
+                            cfg.max_sample_rate().0,
+                            cfg.sample_format()
+                        );
+                    }
+                }
+                Err(e) => println!("    Error listing configs: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_by_name() {
+        let names = ["HD Webcam C270 Mono", "USB Audio", "usb"];
+        let find = |pattern| find_by_name(names.iter(), |n| Some(n.to_string()), pattern);
+        assert_eq!(find("USB"), Some(&"usb"));
+        assert_eq!(find("usb au"), Some(&"USB Audio"));
+        assert_eq!(find("webcam"), Some(&"HD Webcam C270 Mono"));
+        assert_eq!(find("blue yeti"), None);
+    }
+}
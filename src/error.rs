@@ -14,11 +14,53 @@ pub enum AssistantRsError {
     #[error("no microphone found")]
     MicrophoneNotFound,
 
+    #[error("no audio host matching {0} found")]
+    AudioHostNotFound(String),
+
+    #[error("no input device matching {0} found")]
+    InputDeviceNotFound(String),
+
+    #[error("no speaker found")]
+    SpeakerNotFound,
+
+    #[error("no output device matching {0} found")]
+    OutputDeviceNotFound(String),
+
+    #[error("no speech was heard within {0:?}")]
+    ListenTimeout(std::time::Duration),
+
+    #[error("listening was cancelled")]
+    Cancelled,
+
+    #[error("there is no more text to read")]
+    EndOfInput,
+
+    #[error("the speech model is not loaded")]
+    ModelNotLoaded,
+
     #[error("CPAL error")]
     Cpal(#[from] CpalError),
 
     #[error("Error running command")]
     RunError(#[from] std::io::Error),
+
+    #[error("error reading audio file")]
+    Wav(#[from] WavError),
+
+    #[error("error setting up the control socket")]
+    ControlSocket(#[source] std::io::Error),
+}
+
+/// Formats an error along with everything that caused it, like `config error: mode foo is empty`.
+pub fn error_message(err: &dyn std::error::Error) -> String {
+    let mut retvl = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        retvl.push_str(": ");
+        retvl.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    retvl
 }
 
 #[derive(Error, Debug)]
@@ -53,6 +95,24 @@ pub enum ConfigError {
     #[error("mode {0} was defined, but cannot be reached in the mode tree")]
     UnreachableMode(String), 
 
+    #[error("match threshold {0} must be a non-negative number")]
+    InvalidThreshold(f32),
+
+    #[error("invalid slot {0}; slots look like {{name:number}} or {{name:word}}")]
+    InvalidSlot(String),
+
+    #[error("the command for message {0} is empty")]
+    EmptyCommand(String),
+
+    #[error("invalid pronunciation \"{pronunciation}\" for {word}; pronunciations are ARPABET phonemes like \"HH AH0 L OW1\"")]
+    InvalidPronunciation { word: String, pronunciation: String },
+
+    #[error("error reading pronunciation dictionary {0}")]
+    PronunciationDict(std::path::PathBuf, #[source] std::io::Error),
+
+    #[error("no pronunciation is known for {word} in message {message}")]
+    UnknownWord { word: String, message: String },
+
 }
 
 #[derive(Error, Debug)]
@@ -68,6 +128,24 @@ pub enum CpalError {
     #[error("error playing stream")]
     PlayStream(#[from] cpal::PlayStreamError),
 
+    #[error("error getting the device's default config")]
+    DefaultConfig(#[from] cpal::DefaultStreamConfigError),
+
     #[error("error in running stream")]
     Stream(#[from] cpal::StreamError),
 }
+
+#[derive(Error, Debug)]
+pub enum WavError {
+    #[error("error reading WAV file")]
+    Io(#[from] std::io::Error),
+
+    #[error("file is not a RIFF WAVE file")]
+    NotWave,
+
+    #[error("unsupported WAV format: {0}")]
+    UnsupportedFormat(String),
+
+    #[error("WAV sample rate {found} does not match the model's sample rate {expected}")]
+    SampleRateMismatch { found: u32, expected: u32 },
+}
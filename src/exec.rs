@@ -0,0 +1,431 @@
+use crate::error::AssistantRsError;
+use crate::slots::{self, SlotValues};
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{self, Child, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The terminal command a `Command` runs.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandLine {
+    /// A command line passed to the shell, like `command = "firefox youtube.com"`.
+    Shell(String),
+
+    /// A program and its arguments, run directly without a shell, like `command = ["firefox", "youtube.com"]`.
+    Argv(Vec<String>),
+}
+
+impl CommandLine {
+    /// Replaces each `{name}` in the command line with the value captured by the slot `name`.
+    ///
+    /// Values are quoted in shell command lines, and arguments are substituted one by one, so a value
+    /// always stays a single argument and is never run as shell code.
+    pub fn substitute(&self, values: &SlotValues) -> Self {
+        match self {
+            CommandLine::Shell(raw) => CommandLine::Shell(slots::substitute_quoted(raw, values)),
+            CommandLine::Argv(argv) => CommandLine::Argv(
+                argv.iter()
+                    .map(|arg| slots::substitute(arg, values))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Whether there is nothing to run.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            CommandLine::Shell(raw) => raw.trim().is_empty(),
+            CommandLine::Argv(argv) => argv.is_empty(),
+        }
+    }
+
+    /// Builds the process to spawn, using `shell` for shell command lines.
+    fn to_process(&self, shell: &str) -> io::Result<process::Command> {
+        match self {
+            CommandLine::Shell(raw) => {
+                let mut proc = process::Command::new(shell);
+                proc.arg("-c").arg(raw);
+                Ok(proc)
+            }
+            CommandLine::Argv(argv) => {
+                let (program, args) = argv.split_first().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "the command is empty")
+                })?;
+                let mut proc = process::Command::new(program);
+                proc.args(args);
+                Ok(proc)
+            }
+        }
+    }
+}
+
+/// Shows the command line as it would be typed into a shell.
+///
+/// Arguments are quoted where needed, so that where each one starts and ends is unambiguous.
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandLine::Shell(raw) => write!(f, "{}", raw),
+            CommandLine::Argv(argv) => {
+                let args: Vec<_> = argv.iter().map(|arg| display_arg(arg)).collect();
+                write!(f, "{}", args.join(" "))
+            }
+        }
+    }
+}
+
+/// Quotes `arg` for a shell unless it is made only of characters that the shell leaves alone.
+fn display_arg(arg: &str) -> Cow<'_, str> {
+    // Braces are left alone, so that `{name}` slots read the same as in the config.
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-+=:,./@%{}".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(slots::shell_quote(arg))
+    }
+}
+
+/// Controls how a command's process is run.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExecPolicy {
+    /// Wait for the command to exit before carrying on with the run.
+    #[serde(default)]
+    pub wait: Option<bool>,
+
+    /// How long to wait for the command before killing it.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+
+    /// Collect the command's output, logging it and adding it to the run's report.
+    #[serde(default)]
+    pub capture_output: Option<bool>,
+
+    /// Speak the command's output with the `tts-command`.
+    #[serde(default)]
+    pub say_output: Option<bool>,
+
+    /// The directory to run the command in.
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+
+    /// Extra environment variables to set for the command.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// The shell that runs command lines given as a single string.
+    #[serde(default)]
+    pub shell: Option<String>,
+}
+
+impl ExecPolicy {
+    /// Returns whether to wait for the command to exit.
+    ///
+    /// Setting a timeout or capturing the output implies waiting.
+    pub fn wait(&self) -> bool {
+        self.wait.unwrap_or(false) || self.timeout().is_some() || self.capture_output()
+    }
+
+    /// Returns how long to wait for the command before killing it, if it should be killed at all.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    /// Returns whether to collect the command's output.
+    ///
+    /// Defaults to `false`, discarding it. Speaking the output implies capturing it.
+    pub fn capture_output(&self) -> bool {
+        self.capture_output.unwrap_or(false) || self.say_output()
+    }
+
+    /// Returns whether to speak the command's output.
+    ///
+    /// Defaults to `false`.
+    pub fn say_output(&self) -> bool {
+        self.say_output.unwrap_or(false)
+    }
+
+    /// Returns the shell that runs command lines given as a single string.
+    ///
+    /// Defaults to `sh`.
+    pub fn shell(&self) -> &str {
+        self.shell.as_deref().unwrap_or("sh")
+    }
+}
+
+/// How a command that was run ended.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommandStatus {
+    /// The command line that was run, with its slots filled in.
+    pub command: String,
+
+    /// The command's exit code, or `None` if it was not waited for or was killed by a signal.
+    pub exit_code: Option<i32>,
+
+    /// Whether the command was killed for running past its timeout.
+    pub timed_out: bool,
+
+    /// What the command wrote to its standard output, if it was captured.
+    pub stdout: Option<String>,
+
+    /// What the command wrote to its standard error, if it was captured.
+    pub stderr: Option<String>,
+}
+
+/// Runs `line` according to `policy`, with `env` added to its environment.
+///
+/// Unless the policy says to wait for it, the command is left running in the background.
+pub fn run_command(
+    line: &CommandLine,
+    policy: &ExecPolicy,
+    env: &BTreeMap<String, String>,
+) -> Result<CommandStatus, AssistantRsError> {
+    run_command_with_input(line, policy, env, None)
+}
+
+/// Runs `line` like `run_command`, writing `input` to its standard input if given.
+pub fn run_command_with_input(
+    line: &CommandLine,
+    policy: &ExecPolicy,
+    env: &BTreeMap<String, String>,
+    input: Option<&str>,
+) -> Result<CommandStatus, AssistantRsError> {
+    let mut proc = line.to_process(policy.shell())?;
+    let stdin = if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    proc.envs(env).envs(&policy.env).stdin(stdin);
+    if let Some(cwd) = policy.cwd.as_ref() {
+        proc.current_dir(cwd);
+    }
+    let output = if policy.capture_output() {
+        Stdio::piped
+    } else {
+        Stdio::null
+    };
+    proc.stdout(output()).stderr(output());
+    #[cfg(target_family = "unix")]
+    {
+        // Give the command its own process group, so that a timeout also kills anything it started.
+        use std::os::unix::process::CommandExt;
+        proc.process_group(0);
+    }
+
+    let mut retvl = CommandStatus {
+        command: line.to_string(),
+        ..CommandStatus::default()
+    };
+    let mut child = proc.spawn()?;
+    if let (Some(mut pipe), Some(input)) = (child.stdin.take(), input) {
+        // Write on another thread, so that the timeout still applies to a command that never reads
+        // all of its input. A command that exits without reading it is not an error.
+        let input = input.to_owned();
+        thread::spawn(move || {
+            let _ = pipe.write_all(input.as_bytes());
+        });
+    }
+    if !policy.wait() {
+        return Ok(retvl);
+    }
+
+    // Read the output while waiting, so that the command never blocks on a full pipe.
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+    let status = wait_timeout(&mut child, policy.timeout())?;
+    retvl.exit_code = status.and_then(|s| s.code());
+    retvl.timed_out = status.is_none();
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    retvl.stdout = stdout.map(|output| collect_output(&output, deadline));
+    retvl.stderr = stderr.map(|output| collect_output(&output, deadline));
+
+    if retvl.timed_out {
+        log::log!(
+            log::Level::Warn,
+            "Command `{}` timed out and was killed.",
+            retvl.command
+        );
+    } else if let Some(status) = status.filter(|s| !s.success()) {
+        log::log!(
+            log::Level::Warn,
+            "Command `{}` failed with {}.",
+            retvl.command,
+            status
+        );
+    }
+    if let Some(out) = retvl.stdout.as_deref().filter(|s| !s.trim().is_empty()) {
+        log::log!(
+            log::Level::Info,
+            "Output of `{}`: {}",
+            retvl.command,
+            out.trim_end()
+        );
+    }
+    if let Some(err) = retvl.stderr.as_deref().filter(|s| !s.trim().is_empty()) {
+        log::log!(
+            log::Level::Warn,
+            "Error output of `{}`: {}",
+            retvl.command,
+            err.trim_end()
+        );
+    }
+    Ok(retvl)
+}
+
+/// How long to keep reading a command's output once it has exited, for whatever is left in the pipe.
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+/// Reads everything from `pipe` on another thread, passing it along as it arrives.
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> crossbeam::Receiver<Vec<u8>> {
+    let (send, recv) = crossbeam::unbounded();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => {
+                    if send.send(buf[..read].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+    recv
+}
+
+/// Collects the output read by `read_in_background`, until the pipe closes or `deadline` passes.
+///
+/// Anything the command started in the background may keep the pipe open long after the command
+/// itself has exited, so the output is not waited on past the deadline.
+fn collect_output(output: &crossbeam::Receiver<Vec<u8>>, deadline: Instant) -> String {
+    let mut buf = Vec::new();
+    while let Ok(chunk) = output.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        buf.extend(chunk);
+    }
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+/// Waits for `child` to exit, killing it if it runs past `timeout`.
+///
+/// Returns `Ok(None)` if the child was killed.
+fn wait_timeout(child: &mut Child, timeout: Option<Duration>) -> io::Result<Option<ExitStatus>> {
+    const POLL_LENGTH: Duration = Duration::from_millis(10);
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Some),
+    };
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            kill(child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_LENGTH);
+    }
+}
+
+/// Kills `child` along with everything else in its process group.
+#[cfg(target_family = "unix")]
+fn kill(child: &mut Child) {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+    if killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL).is_err() {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_command_policy() {
+        let policy = ExecPolicy {
+            capture_output: Some(true),
+            cwd: Some(std::env::temp_dir()),
+            ..ExecPolicy::default()
+        };
+        let mut env = BTreeMap::new();
+        env.insert("ASSISTANT_TEST".to_owned(), "hello".to_owned());
+        let line =
+            CommandLine::Shell("echo $ASSISTANT_TEST; pwd; echo oops >&2; exit 3".to_owned());
+        let status = run_command(&line, &policy, &env).unwrap();
+        assert_eq!(status.exit_code, Some(3));
+        assert!(!status.timed_out);
+        let stdout = status.stdout.unwrap();
+        assert!(stdout.starts_with("hello\n"));
+        let cwd = std::env::temp_dir().canonicalize().unwrap();
+        assert_eq!(
+            PathBuf::from(stdout.lines().nth(1).unwrap())
+                .canonicalize()
+                .unwrap(),
+            cwd
+        );
+        assert_eq!(status.stderr.as_deref(), Some("oops\n"));
+
+        // Arguments are passed as-is, without a shell to split or expand them.
+        let line = CommandLine::Argv(vec!["echo".to_owned(), "$HOME {site}".to_owned()]);
+        let mut values = SlotValues::new();
+        values.insert("site".to_owned(), "a b".to_owned());
+        let status = run_command(&line.substitute(&values), &policy, &env).unwrap();
+        assert_eq!(status.stdout.as_deref(), Some("$HOME a b\n"));
+        assert_eq!(status.command, "echo '$HOME a b'");
+        let line = CommandLine::Argv(vec![
+            "firefox".to_owned(),
+            "youtube.com".to_owned(),
+            "".to_owned(),
+            "don't".to_owned(),
+        ]);
+        assert_eq!(line.to_string(), r"firefox youtube.com '' 'don'\''t'");
+
+        let policy = ExecPolicy {
+            timeout_ms: Some(100),
+            ..ExecPolicy::default()
+        };
+        let start = Instant::now();
+        let line = CommandLine::Shell("sleep 5; sleep 5".to_owned());
+        let status = run_command(&line, &policy, &env).unwrap();
+        assert!(status.timed_out);
+        assert_eq!(status.exit_code, None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Output is not waited on once the command exits, even if something it started holds the pipe.
+        let policy = ExecPolicy {
+            capture_output: Some(true),
+            ..ExecPolicy::default()
+        };
+        let start = Instant::now();
+        let line = CommandLine::Shell("echo started; sleep 5 &".to_owned());
+        let status = run_command(&line, &policy, &env).unwrap();
+        assert_eq!(status.exit_code, Some(0));
+        assert_eq!(status.stdout.as_deref(), Some("started\n"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Without waiting, nothing is known about how the command ended.
+        let line = CommandLine::Shell("exit 3".to_owned());
+        let status = run_command(&line, &ExecPolicy::default(), &env).unwrap();
+        assert_eq!(status.exit_code, None);
+        assert!(!status.timed_out);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::buffer;
+use crate::config::{AudioConfig, CueConfig, CueSound};
+use crate::convert::FormatConverter;
+use crate::devices;
+use crate::error::{AssistantRsError, CpalError};
+use crate::wav::WavFileSource;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::SampleFormat;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The moments the assistant signals with a sound.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Cue {
+    /// The assistant started listening for a command.
+    Listening,
+
+    /// An utterance matched a command.
+    Matched,
+
+    /// An utterance did not match any command.
+    NoMatch,
+
+    /// The assistant switched into a mode, and is listening again.
+    ModeEntered,
+}
+
+/// Plays the sounds for cues.
+pub trait CuePlayer {
+    /// Plays the sound for `cue`, returning once it has finished so that it is not heard by the microphone.
+    fn play(&mut self, cue: Cue) -> Result<(), AssistantRsError>;
+
+    /// Whether the cues make any sound that the microphone could pick up.
+    fn is_audible(&self) -> bool {
+        true
+    }
+}
+
+/// A `CuePlayer` that never makes a sound.
+pub struct NullCuePlayer;
+
+impl CuePlayer for NullCuePlayer {
+    fn play(&mut self, _cue: Cue) -> Result<(), AssistantRsError> {
+        Ok(())
+    }
+
+    fn is_audible(&self) -> bool {
+        false
+    }
+}
+
+/// Plays cues on a speaker.
+pub struct CpalCuePlayer {
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+
+    /// The sound for each cue, as mono samples at the device's sample rate.
+    sounds: HashMap<Cue, Vec<i16>>,
+}
+
+impl CpalCuePlayer {
+    /// Finds the speaker named in the config and loads every cue's sound.
+    pub fn new(audio_conf: &AudioConfig, conf: &CueConfig) -> Result<Self, AssistantRsError> {
+        let device = devices::find_output_device(audio_conf, conf.cue_device.as_deref())?;
+        let config = device.default_output_config().map_err(CpalError::from)?;
+        let sample_rate = config.sample_rate().0;
+        let cues = [
+            (Cue::Listening, conf.listening_cue()),
+            (Cue::Matched, conf.matched_cue()),
+            (Cue::NoMatch, conf.no_match_cue()),
+            (Cue::ModeEntered, conf.mode_cue()),
+        ];
+        let mut sounds = HashMap::new();
+        for (cue, sound) in cues.iter() {
+            sounds.insert(*cue, load_sound(sound, sample_rate)?);
+        }
+        Ok(Self {
+            device,
+            config,
+            sounds,
+        })
+    }
+}
+
+impl CuePlayer for CpalCuePlayer {
+    fn play(&mut self, cue: Cue) -> Result<(), AssistantRsError> {
+        let samples = match self.sounds.get(&cue) {
+            Some(samples) if !samples.is_empty() => samples.clone(),
+            _ => return Ok(()),
+        };
+        let length = buffer::samples_to_duration(samples.len(), self.config.sample_rate().0);
+        let stream = match self.config.sample_format() {
+            SampleFormat::I16 => build_output_stream::<i16>(&self.device, &self.config, samples),
+            SampleFormat::U16 => build_output_stream::<u16>(&self.device, &self.config, samples),
+            SampleFormat::F32 => build_output_stream::<f32>(&self.device, &self.config, samples),
+        }?;
+        stream.play().map_err(CpalError::from)?;
+
+        // Leave some time for the audio still in the device's buffers.
+        std::thread::sleep(length + Duration::from_millis(50));
+        Ok(())
+    }
+}
+
+/// Builds an output stream that plays `samples` on every channel, followed by silence.
+fn build_output_stream<T: cpal::Sample>(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    samples: Vec<i16>,
+) -> Result<cpal::Stream, AssistantRsError> {
+    let channels = usize::from(config.channels().max(1));
+    let mut samples = samples.into_iter();
+    let stream = device
+        .build_output_stream(
+            &config.config(),
+            move |data: &mut [T], _cb| {
+                for frame in data.chunks_mut(channels) {
+                    let value = samples.next().unwrap_or(0);
+                    for out in frame.iter_mut() {
+                        *out = T::from(&value);
+                    }
+                }
+            },
+            |e| log::log!(log::Level::Warn, "Error playing a cue: {}", e),
+        )
+        .map_err(CpalError::from)?;
+    Ok(stream)
+}
+
+/// Loads `sound` as mono samples at `sample_rate`.
+pub fn load_sound(sound: &CueSound, sample_rate: u32) -> Result<Vec<i16>, AssistantRsError> {
+    match sound {
+        CueSound::Tone {
+            tone_hz,
+            duration_ms,
+        } => {
+            let length = Duration::from_millis(*duration_ms);
+            Ok(sine_tone(*tone_hz, length, sample_rate))
+        }
+        CueSound::File { file } => {
+            let mut source = WavFileSource::open_any_rate(file)?;
+            let samples = source.read_all()?;
+            let mut converter = FormatConverter::new(1, source.sample_rate(), sample_rate);
+            Ok(converter.convert(&samples))
+        }
+    }
+}
+
+/// Generates a sine wave at a third of full volume, fading in and out so that it does not click.
+fn sine_tone(frequency: u32, length: Duration, sample_rate: u32) -> Vec<i16> {
+    const AMPLITUDE: f64 = i16::MAX as f64 / 3.0;
+    let len = (length.as_secs_f64() * f64::from(sample_rate)) as usize;
+
+    // Fade over 5 milliseconds, or half the tone if it is shorter than that.
+    let fade_len = (sample_rate as usize / 200).min(len / 2).max(1);
+    (0..len)
+        .map(|n| {
+            let phase = n as f64 * 2.0 * std::f64::consts::PI * f64::from(frequency)
+                / f64::from(sample_rate);
+            let fade = n.min(len - 1 - n).min(fade_len) as f64 / fade_len as f64;
+            (phase.sin() * AMPLITUDE * fade) as i16
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sine_tone() {
+        let tone = sine_tone(1000, Duration::from_millis(100), 16000);
+        assert_eq!(tone.len(), 1600);
+
+        // The tone fades in and out, reaching a third of full volume in between.
+        assert_eq!(tone[0], 0);
+        assert_eq!(tone[1599], 0);
+        let peak = tone.iter().map(|s| i32::from(*s).abs()).max().unwrap();
+        assert!((10000..=i32::from(i16::MAX) / 3).contains(&peak));
+        assert!(tone[..20].iter().all(|s| i32::from(*s).abs() < 3000));
+
+        let sound = CueSound::Tone {
+            tone_hz: 440,
+            duration_ms: 250,
+        };
+        assert_eq!(load_sound(&sound, 48000).unwrap().len(), 12000);
+    }
+}
@@ -1,14 +1,29 @@
 mod args;
 mod buffer;
+mod check;
 mod config;
 mod context;
+#[cfg(target_family = "unix")]
+mod control;
+mod convert;
+mod devices;
 mod error;
+mod exec;
+mod feedback;
 mod metrics;
 mod modes;
+mod recognizer;
+mod slots;
 mod speech;
+#[cfg(test)]
+mod testing;
+mod tts;
 mod utils;
-use crate::args::Args;
-use crate::context::AssistantContext;
+mod vad;
+mod wav;
+use crate::args::{Args, Subcommand};
+use crate::context::{AssistantContext, AudioInput};
+use crate::error::AssistantRsError;
 
 use structopt::StructOpt;
 
@@ -22,47 +37,277 @@ fn main() {
     )
     .unwrap();
     let args = Args::from_args();
+    if args.list_devices() {
+        devices::list_devices();
+        return;
+    }
+    if let Some(subcommand) = args.subcommand() {
+        run_subcommand(subcommand, args.conf_paths().collect());
+        return;
+    }
+    if args.text().is_some() || args.stdin() {
+        let paths = args.conf_paths().collect();
+        let ctx = match AssistantContext::init_text_from_paths(paths) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                eprintln!("Error: {}", error::error_message(&e));
+                std::process::exit(1);
+            }
+        };
+        run_text(ctx, args.text());
+        return;
+    }
         let paths = args.conf_paths().collect();
         let mut ctx = AssistantContext::init_from_paths(paths).unwrap();
+    if let Some(pt) = args.input_file() {
+        ctx = ctx.with_input(AudioInput::WavFile(pt.to_owned()));
+    }
     if args.daemonize() {
-        run_daemon(ctx)
+        run_daemon(ctx, args.socket())
     } else {
-        ctx.run().unwrap();
+        match ctx.run() {
+            Err(AssistantRsError::ListenTimeout(timeout)) => {
+                log::log!(log::Level::Info, "No speech heard within {:?}.", timeout);
+            }
+            other => {
+                other.unwrap();
+            }
+        }
+    }
+}
+
+/// Runs a config tool instead of the assistant.
+fn run_subcommand(subcommand: &Subcommand, paths: Vec<std::path::PathBuf>) {
+    match subcommand {
+        Subcommand::Match { text, mode, json } => {
+            let config = match config::cascade_configs(&paths) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Error: {}", error::error_message(&e));
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = config.modes.verify() {
+                eprintln!("Error: {}", error::error_message(&e));
+                std::process::exit(1);
+            }
+            if let Some(name) = mode.as_deref() {
+                if !config.modes.has_mode(name) {
+                    eprintln!("Error: mode {} does not exist.", name);
+                    std::process::exit(1);
+                }
+            }
+            let explanation = context::explain_match(&config.modes, mode.as_deref(), text);
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&explanation).unwrap());
+            } else {
+                print!("{}", explanation);
+            }
+        }
+        Subcommand::CheckConfig => {
+            let check = check::check_configs(&paths);
+            for pt in check.read.iter() {
+                println!("Read {}", pt.display());
+            }
+            for pt in check.skipped.iter() {
+                println!("Skipped {}, which does not exist", pt.display());
+            }
+            for problem in check.errors.iter() {
+                println!("error: {}", problem);
+            }
+            for problem in check.warnings.iter() {
+                println!("warning: {}", problem);
+            }
+            println!(
+                "{} error(s), {} warning(s)",
+                check.errors.len(),
+                check.warnings.len()
+            );
+            if !check.errors.is_empty() {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Runs the command matching `text`, or each line of standard input if there is no `text`.
+///
+/// Lines left over once a run ends start the next one, until standard input runs out. A run that
+/// fails is logged, and the next line starts a new one.
+fn run_text(mut ctx: AssistantContext, text: Option<&str>) {
+    use crate::error::error_message;
+    use crate::modes::Action;
+    use std::io::BufRead;
+
+    if let Some(text) = text {
+        if let Err(e) = ctx.run_with_text(&mut std::iter::once(text.to_owned()), None) {
+            eprintln!("Error: {}", error_message(&e));
+            std::process::exit(1);
+        }
+        return;
+    }
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines().map_while(Result::ok).peekable();
+    while lines.peek().is_some() {
+        match ctx.run_with_text(&mut lines, None) {
+            Ok(report) => {
+                if report.actions.contains(&Action::ExitDaemon) {
+                    break;
+                }
+            }
+            Err(e) => {
+                log::log!(log::Level::Error, "Error: {}", error_message(&e));
+            }
+        }
     }
 }
 
 #[cfg(not(target_family = "unix"))]
-fn run_daemon(ctx : AssistantContext) {
+fn run_daemon(ctx : AssistantContext, socket: Option<&std::path::Path>) {
     eprintln!("Error: daemonization is not currently supported on this opperating system.");
     std::process::exit(-1);
 }
 
 #[cfg(target_family = "unix")]
-fn run_daemon(mut ctx : AssistantContext) {
+fn run_daemon(mut ctx : AssistantContext, socket: Option<&std::path::Path>) {
+    use crate::control::{ControlReply, ControlRequest, ControlServer, DaemonHandle, DaemonJob};
+    use crate::error::error_message;
+    use crate::modes::Action;
     use nix::sys::signal::{SigSet, Signal};
 
+    // Block the signals before spawning anything, so that every thread inherits the mask
+    // and only the signal thread ever sees them.
     let mut waiter = SigSet::empty();
     waiter.add(Signal::SIGUSR1);
     waiter.add(Signal::SIGCONT);
     waiter.add(Signal::SIGHUP);
+    waiter.add(Signal::SIGTERM);
+    waiter.add(Signal::SIGINT);
     waiter.thread_set_mask().unwrap();
-    loop {
-        match waiter.wait() {
+
+    let (jobs, job_recv) = crossbeam::unbounded::<DaemonJob>();
+    let handle = DaemonHandle {
+        jobs,
+        status: Default::default(),
+        cancel: ctx.cancel_flag(),
+        shutting_down: Default::default(),
+    };
+    let signal_handle = handle.clone();
+    std::thread::spawn(move || loop {
+        let request = match waiter.wait() {
             Ok(Signal::SIGHUP) => {
                 log::log!(
                     log::Level::Debug,
                     "Caught a signal to reload the assistant."
                 );
-                ctx.reload().unwrap();
+                ControlRequest::Reload
             }
             Ok(Signal::SIGCONT) | Ok(Signal::SIGUSR1) => {
                 log::log!(log::Level::Debug, "Caught a signal to run the assistant.");
-                ctx.run().unwrap();
+                ControlRequest::Listen
+            }
+            Ok(Signal::SIGTERM) | Ok(Signal::SIGINT) => {
+                log::log!(log::Level::Debug, "Caught a signal to shut down.");
+                signal_handle.begin_shutdown();
+                ControlRequest::Shutdown
+            }
+            Ok(other) => {
+                log::log!(log::Level::Warn, "Ignoring unexpected signal {:?}.", other);
+                continue;
+            }
+            Err(e) => {
+                // Back off, so that an error that keeps happening does not spin or flood the log.
+                log::log!(log::Level::Warn, "Error waiting for signals: {}", e);
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                continue;
+            }
+        };
+        let job = DaemonJob {
+            request,
+            reply: None,
+        };
+        if signal_handle.jobs.send(job).is_err() {
+            break;
+        }
+    });
+    let _server = match socket.map(|pt| ControlServer::start(pt, handle.clone())).transpose() {
+        Ok(server) => server,
+        Err(e) => {
+            log::log!(log::Level::Error, "Error: {}", error_message(&e));
+            std::process::exit(1);
+        }
+    };
+
+    for job in job_recv.iter() {
+        let mut is_shutdown = job.request == ControlRequest::Shutdown;
+        let res = run_daemon_job(&mut ctx, &handle, job.request);
+        if let Ok(ControlReply::Run(report)) = &res {
+            is_shutdown |= report.actions.contains(&Action::ExitDaemon);
+        }
+        match &res {
+            Err(AssistantRsError::ListenTimeout(timeout)) => {
+                log::log!(log::Level::Info, "No speech heard within {:?}.", timeout);
+            }
+            Err(AssistantRsError::Cancelled) => {
+                log::log!(log::Level::Info, "Listening was cancelled.");
             }
-            Ok(other) => panic!("INVALID SIGNAL: {:?}", other),
             Err(e) => {
-                panic!("GOT WEIRD: {:?}", e);
+                log::log!(log::Level::Error, "Error: {}", error_message(e));
             }
+            Ok(_) => {}
+        }
+        if let Some(reply) = job.reply {
+            let _ = reply.send(res.as_ref().map_or_else(ControlReply::from, |r| r.clone()));
+        }
+        if is_shutdown {
+            log::log!(log::Level::Info, "Shutting down.");
+            break;
         }
     }
+
+    // Dropping the context releases the model; the microphone is already closed between runs.
+    drop(ctx);
+}
+
+/// Carries out a request that needs the assistant itself, updating the daemon's status while it runs.
+#[cfg(target_family = "unix")]
+fn run_daemon_job(
+    ctx: &mut AssistantContext,
+    handle: &crate::control::DaemonHandle,
+    request: crate::control::ControlRequest,
+) -> Result<crate::control::ControlReply, AssistantRsError> {
+    use crate::control::{ControlReply, ControlRequest, DaemonState, DaemonStatus};
+    use std::sync::atomic::Ordering;
+
+    let set_status = |state, mode: Option<&str>| {
+        let mut status = handle.status.lock().unwrap_or_else(|e| e.into_inner());
+        if state == DaemonState::Listening && !handle.shutting_down.load(Ordering::SeqCst) {
+            // `cancel` requests are only accepted while listening, and take the status lock to do so,
+            // so clearing the flag here drops stale ones without losing any meant for this run.
+            handle.cancel.store(false, Ordering::SeqCst);
+        }
+        *status = DaemonStatus {
+            state,
+            mode: mode.map(|s| s.to_owned()),
+        };
+    };
+    let res = match &request {
+        ControlRequest::Listen | ControlRequest::ListenInMode { .. } => {
+            let mode = match &request {
+                ControlRequest::ListenInMode { mode } => Some(mode.as_str()),
+                _ => None,
+            };
+            set_status(DaemonState::Listening, mode);
+            ctx.run_in_mode(mode).map(ControlReply::Run)
+        }
+        ControlRequest::Reload => {
+            set_status(DaemonState::Reloading, None);
+            ctx.reload().map(|_| ControlReply::Ok)
+        }
+        ControlRequest::Status | ControlRequest::Cancel | ControlRequest::Shutdown => {
+            Ok(ControlReply::Ok)
+        }
+    };
+    set_status(DaemonState::Idle, None);
+    res
 }
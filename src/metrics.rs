@@ -1,7 +1,285 @@
+use crate::slots::{MessagePart, MessagePattern, SlotValues, SpokenWords};
+use crate::speech::{PhonePart, Utterance};
+use arpabet::phoneme::Phoneme;
+
+#[allow(unused)]
 pub fn leven_dist(command: &str, text: &str) -> usize {
     wagner_lev(command, text, false)
 }
 
+/// The cost of inserting, deleting, or substituting unrelated phonemes in `phone_dist`.
+pub const PHONE_EDIT_COST: usize = 4;
+
+/// The cost of inserting or deleting a word break in `phone_dist`, so that "fire fox" and
+/// "firefox" are close.
+const SPACE_EDIT_COST: usize = 1;
+
+/// Calculates the phonetic edit distance between two utterances.
+///
+/// Substituting similar-sounding phonemes, like `P` and `B` or two vowels, is cheaper than
+/// substituting unrelated ones. Words without a known pronounciation are compared by their
+/// spelling instead.
+pub fn phone_dist(command: &Utterance, text: &Utterance) -> usize {
+    let command = to_tokens(command.phones());
+    let text = to_tokens(text.phones());
+    weighted_lev(&command, &text, token_sub_cost, token_edit_cost)
+}
+
+/// Calculates `phone_dist` as a fraction of the cost of mispronouncing all of `command`, so that
+/// long and short commands can be compared against the same threshold.
+///
+/// A value of `0.0` is an exact match, while values of `1.0` and above are no better than silence.
+#[allow(unused)]
+pub fn normalized_phone_dist(command: &Utterance, text: &Utterance) -> f32 {
+    let command_cost: usize = to_tokens(command.phones())
+        .iter()
+        .map(token_edit_cost)
+        .sum();
+    phone_dist(command, text) as f32 / command_cost.max(1) as f32
+}
+
+/// The result of matching a transcript against a `MessagePattern`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternMatch {
+    /// The phonetic edit distance between the pattern's fixed words and the transcript.
+    pub distance: usize,
+
+    /// The value each of the pattern's slots captured.
+    pub slots: SlotValues,
+}
+
+/// Calculates the `phone_dist` between a transcript and a pattern whose slots are filled from the transcript,
+/// choosing the words each slot captures so that the distance is as small as possible.
+///
+/// Returns `None` if there is no way to fill every slot, like a number slot in a transcript without numbers.
+pub fn pattern_dist(pattern: &MessagePattern, text: &SpokenWords) -> Option<PatternMatch> {
+    // Without any slots, this is just the distance to the whole transcript.
+    let literal = match pattern.parts() {
+        [] => Some(Utterance::default()),
+        [MessagePart::Literal(phones)] => Some(phones.clone()),
+        _ => None,
+    };
+    if let Some(phones) = literal {
+        return Some(PatternMatch {
+            distance: phone_dist(&phones, &text.phones(0, text.len())),
+            slots: SlotValues::new(),
+        });
+    }
+
+    // `best[p][i]` is the cheapest way for the first `p` parts to cover the first `i` words,
+    // along with the values captured so far.
+    let n = text.len();
+    let delete_cost = |start: usize, end: usize| -> usize {
+        let phones = text.phones(start, end);
+        let cost: usize = to_tokens(phones.phones()).iter().map(token_edit_cost).sum();
+        // Deleting words from next to a slot also deletes the space between them.
+        if start < end {
+            cost + SPACE_EDIT_COST
+        } else {
+            cost
+        }
+    };
+    let mut best: Vec<Option<(usize, SlotValues)>> = (0..=n)
+        .map(|i| Some((delete_cost(0, i), SlotValues::new())))
+        .collect();
+    for part in pattern.parts() {
+        let mut next: Vec<Option<(usize, SlotValues)>> = vec![None; n + 1];
+        for (start, prev) in best.iter().enumerate() {
+            let (prev_cost, prev_slots) = match prev {
+                Some(prev) => prev,
+                None => continue,
+            };
+            for (end, best_end) in next.iter_mut().enumerate().skip(start) {
+                let candidate = match part {
+                    MessagePart::Literal(phones) => {
+                        let cost = phone_dist(phones, &text.phones(start, end));
+                        (prev_cost + cost, prev_slots.clone())
+                    }
+                    MessagePart::Slot { name, kind } => {
+                        let value = match kind.capture(text.words(start, end)) {
+                            Some(value) => value,
+                            None => continue,
+                        };
+                        let mut slots = prev_slots.clone();
+                        slots.insert(name.to_owned(), value);
+                        (*prev_cost, slots)
+                    }
+                };
+                let is_better = match best_end {
+                    Some((cost, _)) => candidate.0 < *cost,
+                    None => true,
+                };
+                if is_better {
+                    *best_end = Some(candidate);
+                }
+            }
+        }
+        best = next;
+    }
+    (0..=n)
+        .filter_map(|end| {
+            let (cost, slots) = best[end].as_ref()?;
+            Some((cost + delete_cost(end, n), slots))
+        })
+        .min_by_key(|(cost, _)| *cost)
+        .map(|(distance, slots)| PatternMatch {
+            distance,
+            slots: slots.clone(),
+        })
+}
+
+/// Calculates `pattern_dist` as a fraction of the cost of mispronouncing all of the pattern's fixed words,
+/// like `normalized_phone_dist`.
+pub fn normalized_pattern_dist(
+    pattern: &MessagePattern,
+    text: &SpokenWords,
+) -> Option<(f32, SlotValues)> {
+    let matched = pattern_dist(pattern, text)?;
+    let pattern_cost: usize = pattern
+        .parts()
+        .iter()
+        .filter_map(|part| match part {
+            MessagePart::Literal(phones) => Some(phones),
+            MessagePart::Slot { .. } => None,
+        })
+        .flat_map(|phones| to_tokens(phones.phones()))
+        .map(|token| token_edit_cost(&token))
+        .sum();
+    let distance = matched.distance as f32 / pattern_cost.max(1) as f32;
+    Some((distance, matched.slots))
+}
+
+/// The unit of comparison in `phone_dist`; unknown words are broken down into their letters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PhoneToken<'a> {
+    Phoneme(&'a Phoneme),
+    Space,
+    Letter(char),
+}
+
+fn to_tokens(phones: &[PhonePart]) -> Vec<PhoneToken<'_>> {
+    let mut retvl = Vec::with_capacity(phones.len());
+    for p in phones {
+        match p {
+            PhonePart::Phoneme(ph) => retvl.push(PhoneToken::Phoneme(ph)),
+            PhonePart::Space => retvl.push(PhoneToken::Space),
+            PhonePart::Unknown(word) => retvl.extend(
+                word.chars()
+                    .flat_map(char::to_lowercase)
+                    .map(PhoneToken::Letter),
+            ),
+        }
+    }
+    retvl
+}
+
+fn token_edit_cost(token: &PhoneToken) -> usize {
+    match token {
+        PhoneToken::Space => SPACE_EDIT_COST,
+        _ => PHONE_EDIT_COST,
+    }
+}
+
+fn token_sub_cost(a: &PhoneToken, b: &PhoneToken) -> usize {
+    match (a, b) {
+        (PhoneToken::Phoneme(a), PhoneToken::Phoneme(b)) => phoneme_sub_cost(a, b),
+        (PhoneToken::Space, PhoneToken::Space) => 0,
+        (PhoneToken::Letter(a), PhoneToken::Letter(b)) if a == b => 0,
+        _ => PHONE_EDIT_COST,
+    }
+}
+
+/// Broad articulatory classes used to decide how similar two phonemes sound.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PhoneClass {
+    Vowel,
+    Stop,
+    Fricative,
+    Affricate,
+    Nasal,
+    Liquid,
+    Glide,
+}
+
+/// Pairs of consonants that differ only in voicing.
+const VOICING_PAIRS: &[(&str, &str)] = &[
+    ("P", "B"),
+    ("T", "D"),
+    ("K", "G"),
+    ("F", "V"),
+    ("TH", "DH"),
+    ("S", "Z"),
+    ("SH", "ZH"),
+    ("CH", "JH"),
+];
+
+/// Strips the stress marker off of an ARPABET vowel.
+fn base_symbol(ph: &Phoneme) -> &'static str {
+    ph.to_str().trim_end_matches(|c: char| c.is_ascii_digit())
+}
+
+fn phone_class(symbol: &str) -> Option<PhoneClass> {
+    let class = match symbol {
+        "AA" | "AE" | "AH" | "AO" | "AW" | "AY" | "EH" | "ER" | "EY" | "IH" | "IY" | "OW"
+        | "OY" | "UH" | "UW" => PhoneClass::Vowel,
+        "P" | "B" | "T" | "D" | "K" | "G" => PhoneClass::Stop,
+        "F" | "V" | "TH" | "DH" | "S" | "Z" | "SH" | "ZH" | "HH" => PhoneClass::Fricative,
+        "CH" | "JH" => PhoneClass::Affricate,
+        "M" | "N" | "NG" => PhoneClass::Nasal,
+        "L" | "R" => PhoneClass::Liquid,
+        "W" | "Y" => PhoneClass::Glide,
+        _ => return None,
+    };
+    Some(class)
+}
+
+fn phoneme_sub_cost(a: &Phoneme, b: &Phoneme) -> usize {
+    let a = base_symbol(a);
+    let b = base_symbol(b);
+    if a == b {
+        return 0;
+    }
+    let is_voicing_pair = VOICING_PAIRS
+        .iter()
+        .any(|&(x, y)| (x == a && y == b) || (x == b && y == a));
+    if is_voicing_pair {
+        return 1;
+    }
+    match (phone_class(a), phone_class(b)) {
+        (Some(ca), Some(cb)) if ca == cb => 2,
+        _ => PHONE_EDIT_COST,
+    }
+}
+
+/// Wagner-Fischer edit distance over arbitrary tokens with custom costs.
+fn weighted_lev<T>(
+    command: &[T],
+    text: &[T],
+    sub_cost: impl Fn(&T, &T) -> usize,
+    edit_cost: impl Fn(&T) -> usize,
+) -> usize {
+    let mut buf: TwoDimBuffer<usize> =
+        TwoDimBuffer::new_with_size(text.len() + 1, command.len() + 1);
+    for xidx in 1..text.len() + 1 {
+        *buf.get_mut(xidx, 0).unwrap() =
+            buf.get(xidx - 1, 0).copied().unwrap() + edit_cost(&text[xidx - 1]);
+    }
+    for yidx in 1..command.len() + 1 {
+        *buf.get_mut(0, yidx).unwrap() =
+            buf.get(0, yidx - 1).copied().unwrap() + edit_cost(&command[yidx - 1]);
+    }
+    for yidx in 1..command.len() + 1 {
+        for xidx in 1..text.len() + 1 {
+            let subs = buf.get(xidx - 1, yidx - 1).copied().unwrap()
+                + sub_cost(&command[yidx - 1], &text[xidx - 1]);
+            let del = buf.get(xidx - 1, yidx).copied().unwrap() + edit_cost(&text[xidx - 1]);
+            let ins = buf.get(xidx, yidx - 1).copied().unwrap() + edit_cost(&command[yidx - 1]);
+            *buf.get_mut(xidx, yidx).unwrap() = ins.min(del).min(subs);
+        }
+    }
+    buf.buffer.last().copied().unwrap()
+}
+
 // https://en.wikipedia.org/wiki/Wagner%E2%80%93Fischer_algorithm
 // http://ginstrom.com/scribbles/2007/12/01/fuzzy-substring-matching-with-levenshtein-distance-in-python/
 fn wagner_lev(command: &str, text: &str, substring_match: bool) -> usize {
@@ -63,12 +341,74 @@ impl<T: Default> TwoDimBuffer<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::speech::Pronunciations;
     #[test]
     fn test_leven() {
         let wa = "sunday";
         let wb = "saturday";
         assert_eq!(3, leven_dist(&wa, &wb));
     }
+    #[test]
+    fn test_phone_dist() {
+        let firefox = Utterance::parse("firefox").unwrap();
+        let fire_fox = Utterance::parse("fire fox").unwrap();
+        let fire_fax = Utterance::parse_with_unknowns("fire fax");
+        assert_eq!(0, phone_dist(&firefox, &firefox));
+        assert!(phone_dist(&fire_fox, &firefox) <= SPACE_EDIT_COST);
+        assert!(phone_dist(&fire_fox, &firefox) < phone_dist(&fire_fax, &firefox));
+
+        // Similar phonemes are cheaper to swap than unrelated ones.
+        let tee = Utterance::parse("tee").unwrap();
+        let dee = Utterance::parse("dee").unwrap();
+        let me = Utterance::parse("me").unwrap();
+        assert!(phone_dist(&tee, &dee) < phone_dist(&tee, &me));
+
+        // Words whose pronounciation cannot even be guessed fall back to their spelling.
+        let a = Utterance::parse_with_unknowns("qw#xz");
+        let b = Utterance::parse_with_unknowns("qw#xy");
+        assert_eq!(PHONE_EDIT_COST, phone_dist(&a, &b));
+    }
+
+    #[test]
+    fn test_normalized_phone_dist() {
+        let fire_fox = Utterance::parse("fire fox").unwrap();
+        let silence = Utterance::default();
+        assert_eq!(0.0, normalized_phone_dist(&fire_fox, &fire_fox));
+        assert_eq!(1.0, normalized_phone_dist(&fire_fox, &silence));
+        let unrelated = Utterance::parse("new window").unwrap();
+        assert!(normalized_phone_dist(&fire_fox, &unrelated) > 0.5);
+    }
+
+    #[test]
+    fn test_pattern_dist() {
+        let dict = Pronunciations::default();
+        let volume = MessagePattern::parse("volume {level:number}").unwrap();
+        let text = SpokenWords::new_in("volume twenty five", &dict);
+        let matched = pattern_dist(&volume, &text).unwrap();
+        assert_eq!(matched.distance, 0);
+        assert_eq!(matched.slots["level"], "25");
+
+        // Extra words cost the same as they would without the slot.
+        let text = SpokenWords::new_in("volume thirty please", &dict);
+        let matched = pattern_dist(&volume, &text).unwrap();
+        assert_eq!(matched.slots["level"], "30");
+        assert!(matched.distance > 0);
+
+        // A number slot needs a number.
+        assert!(pattern_dist(&volume, &SpokenWords::new_in("volume up", &dict)).is_none());
+
+        // Patterns without slots are plain `phone_dist`s.
+        let firefox = MessagePattern::parse("fire fox").unwrap();
+        let text = SpokenWords::new_in("firefox", &dict);
+        assert_eq!(
+            pattern_dist(&firefox, &text).unwrap().distance,
+            phone_dist(
+                &Utterance::parse("fire fox").unwrap(),
+                &Utterance::parse("firefox").unwrap()
+            )
+        );
+    }
+
     #[test]
     fn test_substring_leven() {
         let wa = "day";
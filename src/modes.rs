@@ -1,11 +1,15 @@
-use crate::error::{ConfigError, PhonemeConvertionError};
-use crate::speech::Utterance;
+use crate::error::ConfigError;
+use crate::exec::{CommandLine, ExecPolicy};
+use crate::slots::MessagePattern;
+use crate::speech::{self, Pronunciations};
 use crate::utils::StringVisitor;
 use serde::de;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct ModeTree {
     #[serde(default)]
     #[serde(rename = "command")]
@@ -14,6 +18,33 @@ pub struct ModeTree {
     #[serde(default)]
     #[serde(rename = "mode")]
     submodes: Vec<CommandMode>,
+
+    #[serde(default)]
+    #[serde(rename = "global-command")]
+    global_commands: Vec<Command>,
+
+    #[serde(default)]
+    #[serde(rename = "match-threshold")]
+    match_threshold: Option<f32>,
+
+    #[serde(default)]
+    #[serde(rename = "on-no-match")]
+    on_no_match: Option<String>,
+
+    #[serde(default)]
+    pronunciations: BTreeMap<String, String>,
+
+    #[serde(default)]
+    #[serde(rename = "pronunciation-dict")]
+    pronunciation_dict: Option<PathBuf>,
+
+    #[serde(default)]
+    #[serde(rename = "require-pronunciations")]
+    require_pronunciations: Option<bool>,
+
+    /// The words loaded from `pronunciations` and `pronunciation-dict`.
+    #[serde(skip)]
+    dictionary: Pronunciations,
 }
 
 impl Default for ModeTree {
@@ -27,6 +58,13 @@ impl ModeTree {
         Self {
             default_mode_commands: Vec::new(),
             submodes: Vec::new(),
+            global_commands: Vec::new(),
+            match_threshold: None,
+            on_no_match: None,
+            pronunciations: BTreeMap::new(),
+            pronunciation_dict: None,
+            require_pronunciations: None,
+            dictionary: Pronunciations::default(),
         }
     }
     pub fn with_commands(mut self, commands: Vec<Command>) -> Result<Self, ConfigError> {
@@ -34,11 +72,11 @@ impl ModeTree {
         let mut messages = HashSet::new();
         let mut new_default_mode = std::mem::take(&mut self.default_mode_commands);
         new_default_mode.append(&mut commands);
-        for cmd in new_default_mode.iter() {
-            if messages.contains(cmd.message()) {
-                return Err(ConfigError::DuplicateMessage(cmd.message().to_owned()));
+        for msg in new_default_mode.iter().flat_map(|cmd| cmd.messages()) {
+            if messages.contains(msg) {
+                return Err(ConfigError::DuplicateMessage(msg.to_owned()));
             } else {
-                messages.insert(cmd.message());
+                messages.insert(msg);
             }
         }
         self.default_mode_commands = new_default_mode;
@@ -49,13 +87,30 @@ impl ModeTree {
         if self.has_mode(&name) {
             return Err(ConfigError::DuplicateMode(name));
         }
-        let new_mode = CommandMode { name, commands };
+        let new_mode = CommandMode {
+            name,
+            commands,
+            ..CommandMode::default()
+        };
         self.submodes.push(new_mode);
         Ok(self)
     }
+    /// Adds commands that can be run from every mode, including the root mode.
+    #[allow(unused)]
+    pub fn with_global_commands(mut self, commands: Vec<Command>) -> Result<Self, ConfigError> {
+        self.global_commands.extend(commands);
+        let mut messages = HashSet::new();
+        for msg in self.global_commands.iter().flat_map(|cmd| cmd.messages()) {
+            if !messages.insert(msg) {
+                return Err(ConfigError::DuplicateMessage(msg.to_owned()));
+            }
+        }
+        Ok(self)
+    }
     pub fn or_else(self, other: Self) -> Result<Self, ConfigError> {
         let mut other = other;
         let mut retvl = self.with_commands(other.default_mode_commands)?;
+        retvl = retvl.with_global_commands(std::mem::take(&mut other.global_commands))?;
         let mut new_submodes = std::mem::take(&mut retvl.submodes);
         new_submodes.append(&mut other.submodes);
         let mut mode_names = HashSet::with_capacity(new_submodes.len());
@@ -68,59 +123,278 @@ impl ModeTree {
             }
         } 
         retvl.submodes = new_submodes;
+        if retvl.match_threshold.is_none() {
+            retvl.match_threshold = other.match_threshold;
+        }
+        if retvl.on_no_match.is_none() {
+            retvl.on_no_match = other.on_no_match;
+        }
+        for (word, phones) in other.pronunciations {
+            retvl.pronunciations.entry(word).or_insert(phones);
+        }
+        if retvl.pronunciation_dict.is_none() {
+            retvl.pronunciation_dict = other.pronunciation_dict;
+        }
+        if retvl.require_pronunciations.is_none() {
+            retvl.require_pronunciations = other.require_pronunciations;
+        }
         Ok(retvl)
     }
-    fn has_mode(&self, name: &str) -> bool {
+
+    /// Loads the words in `pronunciations` and `pronunciation-dict`, and works out the pronounciation
+    /// of every message again using them.
+    ///
+    /// Messages are first parsed while their config file is read, before every file's words are known,
+    /// so this must be called once the config files have been combined.
+    pub fn load_pronunciations(&mut self) -> Result<(), ConfigError> {
+        let mut dictionary = Pronunciations::default();
+        for (word, raw) in self.pronunciations.iter() {
+            let phones =
+                speech::parse_phonemes(raw).ok_or_else(|| ConfigError::InvalidPronunciation {
+                    word: word.to_owned(),
+                    pronunciation: raw.to_owned(),
+                })?;
+            dictionary.insert(word, phones);
+        }
+        if let Some(pt) = self.pronunciation_dict.as_ref() {
+            let raw = std::fs::read_to_string(pt)
+                .map_err(|e| ConfigError::PronunciationDict(pt.to_owned(), e))?;
+            dictionary = dictionary.or_else(Pronunciations::parse_cmudict(&raw)?);
+        }
+        let commands = self
+            .default_mode_commands
+            .iter_mut()
+            .chain(self.global_commands.iter_mut())
+            .chain(self.submodes.iter_mut().flat_map(|md| md.commands.iter_mut()));
+        for cmd in commands {
+            for msg in std::iter::once(&mut cmd.message).chain(cmd.aliases.iter_mut()) {
+                msg.pattern = MessagePattern::parse_in(&msg.raw, &dictionary)?;
+            }
+        }
+        self.dictionary = dictionary;
+        Ok(())
+    }
+
+    /// Returns the pronounciations loaded by `load_pronunciations`, which are used before CMUdict.
+    pub fn pronunciations(&self) -> &Pronunciations {
+        &self.dictionary
+    }
+
+    /// Returns whether every word in every message must have a known pronounciation.
+    ///
+    /// Defaults to `false`, so that words without one can still be matched by their spelling.
+    pub fn require_pronunciations(&self) -> bool {
+        self.require_pronunciations.unwrap_or(false)
+    }
+
+    /// Finds every word in a message that is not in `pronunciations`, `pronunciation-dict` or
+    /// CMUdict, and whose pronounciation can then only be guessed from its spelling.
+    pub fn unknown_words(&self) -> Vec<ConfigError> {
+        let mut retvl = Vec::new();
+        for cmd in self.all_commands() {
+            for message in cmd.messages() {
+                let unknown_words = message
+                    .split_whitespace()
+                    .filter(|word| !word.contains('{') && !word.contains('}'))
+                    .filter(|word| self.dictionary.get(word).is_none());
+                for word in unknown_words {
+                    retvl.push(ConfigError::UnknownWord {
+                        word: word.to_owned(),
+                        message: message.to_owned(),
+                    });
+                }
+            }
+        }
+        retvl
+    }
+    pub fn has_mode(&self, name: &str) -> bool {
         self.submodes.iter().any(|md| md.name == name)
     }
+    /// Returns the commands that can be matched while listening in `mode`: the mode's own commands,
+    /// followed by the global commands unless the mode opts out of them.
     pub fn commands_for_mode<'a, 'b>(
         &'a self,
         mode: Option<&'b str>,
     ) -> impl Iterator<Item = &'a Command> + 'a {
-        let retvl = mode
-            .and_then(|m| self.submodes.iter().find(|p| p.name == m.as_ref()))
+        let submode = mode.and_then(|m| self.submodes.iter().find(|p| p.name == m));
+        let own_commands = submode
             .map(|md| md.commands.iter())
             .unwrap_or_else(|| self.default_mode_commands.iter());
-        retvl
+        let global_commands = match submode {
+            Some(md) if !md.inherit_global.unwrap_or(true) => &[],
+            _ => &self.global_commands[..],
+        };
+        own_commands.chain(global_commands.iter())
+    }
+    /// Returns the maximum normalized distance an utterance may be from a command
+    /// while listening in `mode`, or `None` if every utterance should match something.
+    ///
+    /// A mode's own threshold takes priority over the global one.
+    pub fn threshold_for_mode(&self, mode: Option<&str>) -> Option<f32> {
+        mode.and_then(|m| self.submodes.iter().find(|p| p.name == m))
+            .and_then(|md| md.match_threshold)
+            .or(self.match_threshold)
+    }
+
+    /// Returns how long to wait for speech while listening in `mode`, and what to do if nobody speaks in time.
+    ///
+    /// The root mode has no timeout of its own; it uses the `start-timeout-ms` listen setting.
+    pub fn timeout_for_mode(&self, mode: Option<&str>) -> Option<(Duration, OnTimeout)> {
+        let md = self.submodes.iter().find(|p| Some(p.name.as_str()) == mode)?;
+        let timeout = Duration::from_millis(md.timeout_ms?);
+        Some((timeout, md.on_timeout.unwrap_or_default()))
+    }
+
+    /// Returns what to say when switching into `mode`, like a prompt for what can be said next.
+    pub fn prompt_for_mode(&self, mode: &str) -> Option<&str> {
+        self.submodes
+            .iter()
+            .find(|p| p.name == mode)
+            .and_then(|md| md.say.as_deref())
     }
+
+    /// Returns the terminal command to run when an utterance does not match any command.
+    pub fn on_no_match(&self) -> Option<&str> {
+        self.on_no_match.as_ref().map(|s| s.as_ref())
+    }
+
     pub fn verify(&self) -> Result<(), ConfigError> {
+        match self.problems().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Finds every problem that `verify` would report, in the order it would find them.
+    pub fn problems(&self) -> Vec<ConfigError> {
+        let mut retvl = Vec::new();
         if self.default_mode_commands.is_empty() {
-            return Err(ConfigError::NoCommands);
+            retvl.push(ConfigError::NoCommands);
+        }
+        let thresholds = self
+            .submodes
+            .iter()
+            .filter_map(|md| md.match_threshold)
+            .chain(self.match_threshold);
+        for threshold in thresholds {
+            if threshold.is_nan() || threshold < 0.0 {
+                retvl.push(ConfigError::InvalidThreshold(threshold));
+            }
         }
         let mut mode_keys: HashSet<_> = HashSet::new();
         let mut back_refs : HashSet<_> = HashSet::new();
         for md in &self.submodes {
             if md.commands.is_empty() {
-                return Err(ConfigError::EmptyMode(md.name.to_owned()));
+                retvl.push(ConfigError::EmptyMode(md.name.to_owned()));
             }
             mode_keys.insert(md.name.as_str());
         }
-        let cmd_iter = self
-            .default_mode_commands
-            .iter()
-            .chain(self.submodes.iter().flat_map(|md| md.commands.iter()));
-        let mode_refs = cmd_iter.filter_map(|c| c.next_mode());
+
+        // Global commands are checked once per mode, but each duplicate is only reported once.
+        let mut duplicates = HashSet::new();
+        let all_modes =
+            std::iter::once(None).chain(self.submodes.iter().map(|md| Some(md.name.as_str())));
+        for mode in all_modes {
+            let mut messages = HashSet::new();
+            for msg in self.commands_for_mode(mode).flat_map(|cmd| cmd.messages()) {
+                if !messages.insert(msg) && duplicates.insert(msg) {
+                    retvl.push(ConfigError::DuplicateMessage(msg.to_owned()));
+                }
+            }
+        }
+        for cmd in self.all_commands() {
+            if matches!(cmd.command(), Some(line) if line.is_empty()) {
+                retvl.push(ConfigError::EmptyCommand(cmd.message().to_owned()));
+            }
+        }
+        let mode_refs = self.all_commands().filter_map(|c| c.next_mode());
         for md in mode_refs {
-            if !mode_keys.contains(md) {
-                return Err(ConfigError::ModeNotFound(md.to_owned()));
+            if !mode_keys.contains(md) && !back_refs.contains(md) {
+                retvl.push(ConfigError::ModeNotFound(md.to_owned()));
             }
             back_refs.insert(md);
         }
-        for defined in mode_keys {
-            if !back_refs.contains(defined) {
-                return Err(ConfigError::UnreachableMode(defined.to_owned()));
+        for md in &self.submodes {
+            if !back_refs.contains(md.name.as_str()) {
+                retvl.push(ConfigError::UnreachableMode(md.name.to_owned()));
             }
         }
-        Ok(())
+        if self.require_pronunciations() {
+            retvl.extend(self.unknown_words());
+        }
+        retvl
+    }
+
+    /// Returns every command in the tree: the root mode's, then the global ones, then each mode's.
+    pub fn all_commands(&self) -> impl Iterator<Item = &Command> {
+        self.default_mode_commands
+            .iter()
+            .chain(self.global_commands.iter())
+            .chain(self.submodes.iter().flat_map(|md| md.commands.iter()))
+    }
+
+    /// Returns whether this tree defines or refers to what `err` is about, to find which config
+    /// file a problem came from.
+    pub fn is_source_of(&self, err: &ConfigError) -> bool {
+        match err {
+            ConfigError::EmptyMode(name)
+            | ConfigError::DuplicateMode(name)
+            | ConfigError::UnreachableMode(name) => self.has_mode(name),
+            ConfigError::ModeNotFound(name) => self
+                .all_commands()
+                .any(|cmd| cmd.next_mode() == Some(name.as_str())),
+            ConfigError::DuplicateMessage(msg)
+            | ConfigError::EmptyCommand(msg)
+            | ConfigError::UnknownWord { message: msg, .. } => self
+                .all_commands()
+                .any(|cmd| cmd.messages().any(|m| m == msg)),
+            ConfigError::InvalidThreshold(threshold) => self
+                .submodes
+                .iter()
+                .filter_map(|md| md.match_threshold)
+                .chain(self.match_threshold)
+                .any(|t| t.to_bits() == threshold.to_bits()),
+            ConfigError::InvalidPronunciation { word, .. } => {
+                self.pronunciations.contains_key(word)
+            }
+            ConfigError::PronunciationDict(pt, _) => self.pronunciation_dict.as_ref() == Some(pt),
+            _ => false,
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 struct CommandMode {
     name: String,
     #[serde(default)]
     #[serde(rename = "command")]
     commands: Vec<Command>,
+    #[serde(default)]
+    #[serde(rename = "match-threshold")]
+    match_threshold: Option<f32>,
+    #[serde(default)]
+    #[serde(rename = "inherit-global")]
+    inherit_global: Option<bool>,
+    #[serde(default)]
+    #[serde(rename = "timeout-ms")]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    #[serde(rename = "on-timeout")]
+    on_timeout: Option<OnTimeout>,
+    #[serde(default)]
+    say: Option<String>,
+}
+
+/// What to do when nobody speaks within a mode's `timeout-ms`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnTimeout {
+    /// End the run.
+    #[default]
+    End,
+
+    /// Return to the root mode and keep listening.
+    Root,
 }
 
 /// A single keyphrase-activated action to run.
@@ -128,9 +402,17 @@ struct CommandMode {
 pub struct Command {
     message: CommandMessage,
     #[serde(default)]
-    command: Option<String>,
+    aliases: Vec<CommandMessage>,
+    #[serde(default)]
+    command: Option<CommandLine>,
     #[serde(rename = "mode", default)]
     next_mode: Option<String>,
+    #[serde(default)]
+    action: Option<Action>,
+    #[serde(default)]
+    say: Option<String>,
+    #[serde(flatten)]
+    exec: ExecPolicy,
 }
 
 impl Command {
@@ -145,13 +427,39 @@ impl Command {
     ) -> Self {
         Self {
             message,
-            command,
+            aliases: Vec::new(),
+            command: command.map(CommandLine::Shell),
             next_mode,
+            action: None,
+            say: None,
+            exec: ExecPolicy::default(),
         }
     }
+
+    /// Adds other keyphrases that also run this command.
+    #[allow(unused)]
+    pub fn with_aliases(mut self, aliases: Vec<CommandMessage>) -> Self {
+        self.aliases.extend(aliases);
+        self
+    }
     /// Returns the terminal command that will be run if the keyphrase is matched.
-    pub fn command(&self) -> Option<&str> {
-        self.command.as_ref().map(|s| s.as_ref())
+    pub fn command(&self) -> Option<&CommandLine> {
+        self.command.as_ref()
+    }
+
+    /// Returns how the terminal command is run.
+    pub fn exec_policy(&self) -> &ExecPolicy {
+        &self.exec
+    }
+
+    /// Returns what the assistant will say if the keyphrase is matched, before any slots are filled in.
+    pub fn say(&self) -> Option<&str> {
+        self.say.as_deref()
+    }
+
+    /// Returns the built-in action the assistant will carry out if the keyphrase is matched.
+    pub fn action(&self) -> Option<Action> {
+        self.action
     }
 
     /// Returns the next mode that the model will switch to after this command is run, if it exists.
@@ -164,21 +472,65 @@ impl Command {
     pub fn message(&self) -> &str {
         &self.message.raw
     }
+
+    /// Returns the pronounciation of the keyphrase used to run this command, including any slots.
+    pub fn pattern(&self) -> &MessagePattern {
+        &self.message.pattern
+    }
+
+    /// Returns the keyphrase and every alias that can be used to run this command.
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(&self.message)
+            .chain(self.aliases.iter())
+            .map(|msg| msg.raw.as_str())
+    }
+
+    /// Returns the pronounciations of the keyphrase and every alias, in the same order as `messages`.
+    pub fn patterns(&self) -> impl Iterator<Item = &MessagePattern> {
+        std::iter::once(&self.message)
+            .chain(self.aliases.iter())
+            .map(|msg| &msg.pattern)
+    }
+}
+
+/// An action built into the assistant, run by the assistant itself instead of a shell.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Re-read the config files, ending the run.
+    Reload,
+
+    /// End the run without running anything else that was matched.
+    Cancel,
+
+    /// Return to the mode the current mode was entered from, ending the run in the root mode.
+    Back,
+
+    /// Run the commands from the last matched utterance again.
+    RepeatLast,
+
+    /// Stop the daemon once the run ends; without a daemon, this just ends the run.
+    ExitDaemon,
+
+    /// Log the messages that can be said in the current mode, then keep listening.
+    ListCommands,
 }
 
 /// The keyphrase used to run a command.
+///
+/// Words like `{level:number}` are slots, filled from whatever was said in their place.
 #[derive(Debug, Clone, Serialize)]
 pub struct CommandMessage {
     raw: String,
 
     #[serde(skip_serializing)]
-    phones: Utterance,
+    pattern: MessagePattern,
 }
 
 impl CommandMessage {
-    pub fn from_raw(raw: String) -> Result<Self, PhonemeConvertionError> {
-        let phones = Utterance::parse_with_unknowns(&raw);
-        Ok(Self { raw, phones })
+    pub fn from_raw(raw: String) -> Result<Self, ConfigError> {
+        let pattern = MessagePattern::parse(&raw)?;
+        Ok(Self { raw, pattern })
     }
 }
 
@@ -188,12 +540,7 @@ impl<'de> Deserialize<'de> for CommandMessage {
         D: Deserializer<'de>,
     {
         let raw = deserializer.deserialize_string(StringVisitor::new())?;
-        Self::from_raw(raw).map_err(|e| {
-            de::Error::invalid_value(
-                de::Unexpected::Str(&e.raw),
-                &"a message convertable to a list of phonemes",
-            )
-        })
+        Self::from_raw(raw).map_err(de::Error::custom)
     }
 }
 
@@ -0,0 +1,70 @@
+use crate::config::DeepspeechConfig;
+use crate::error::AssistantRsError;
+use deepspeech::dynamic::{Model, Stream};
+
+/// A speech-to-text engine that can transcribe mono, 16-bit audio.
+pub trait SpeechRecognizer {
+    /// The sample rate, in Hz, that audio fed to this recognizer's streams must use.
+    fn sample_rate(&self) -> u32;
+
+    /// Starts transcribing a new utterance.
+    fn create_stream(&mut self) -> Result<Box<dyn RecognizerStream>, AssistantRsError>;
+}
+
+/// A single in-progress transcription.
+pub trait RecognizerStream {
+    /// Feeds more audio into the transcription.
+    fn feed_audio(&mut self, data: &[i16]);
+
+    /// Gets the transcript of all audio fed so far without finishing the stream.
+    fn intermediate_decode(&mut self) -> Result<String, AssistantRsError>;
+
+    /// Finishes the stream, returning the final transcript.
+    fn finish(self: Box<Self>) -> Result<String, AssistantRsError>;
+}
+
+/// A `SpeechRecognizer` backed by a Mozilla DeepSpeech model.
+pub struct DeepspeechRecognizer {
+    model: Model,
+}
+
+impl DeepspeechRecognizer {
+    /// Loads the DeepSpeech model from the config.
+    pub fn from_config(conf: &DeepspeechConfig) -> Result<Self, AssistantRsError> {
+        let lib = conf.library_path()?;
+        let model = conf.model_path()?;
+        let mut model = Model::load_from_files(lib, model)?;
+        if let Some(scorer) = conf.scorer_path()? {
+            model.enable_external_scorer(scorer)?;
+        }
+        if let Some(w) = conf.beam_width()? {
+            model.set_model_beam_width(w)?;
+        }
+        Ok(Self { model })
+    }
+}
+
+impl SpeechRecognizer for DeepspeechRecognizer {
+    fn sample_rate(&self) -> u32 {
+        self.model.get_sample_rate().unsigned_abs()
+    }
+
+    fn create_stream(&mut self) -> Result<Box<dyn RecognizerStream>, AssistantRsError> {
+        let stream = self.model.create_stream()?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl RecognizerStream for Stream {
+    fn feed_audio(&mut self, data: &[i16]) {
+        Stream::feed_audio(self, data)
+    }
+
+    fn intermediate_decode(&mut self) -> Result<String, AssistantRsError> {
+        Ok(Stream::intermediate_decode(self)?)
+    }
+
+    fn finish(self: Box<Self>) -> Result<String, AssistantRsError> {
+        Ok(Stream::finish(*self)?)
+    }
+}
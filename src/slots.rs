@@ -0,0 +1,407 @@
+use crate::error::ConfigError;
+use crate::speech::{Pronunciations, Utterance};
+use std::collections::BTreeMap;
+
+/// The values captured by a message's slots, by slot name.
+pub type SlotValues = BTreeMap<String, String>;
+
+/// The kinds of values a slot in a command message can capture.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum SlotKind {
+    /// A spoken number, like "twenty five", captured as its digits.
+    Number,
+
+    /// Any single word.
+    Word,
+}
+
+impl SlotKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "number" => Some(SlotKind::Number),
+            "word" => Some(SlotKind::Word),
+            _ => None,
+        }
+    }
+
+    /// Attempts to capture a value of this kind from exactly `words`.
+    pub fn capture(self, words: &[&str]) -> Option<String> {
+        match self {
+            SlotKind::Number => parse_spoken_number(words).map(|n| n.to_string()),
+            SlotKind::Word => match words {
+                [word] => Some((*word).to_owned()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A piece of a command message: either fixed words or a placeholder filled from the transcript.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessagePart {
+    Literal(Utterance),
+    Slot { name: String, kind: SlotKind },
+}
+
+/// The pronounciation of a command message, with placeholders like `{level:number}` kept as slots.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MessagePattern {
+    parts: Vec<MessagePart>,
+}
+
+impl MessagePattern {
+    /// Parses a message, where each `{name:kind}` word is a slot of the given kind.
+    /// A slot written as just `{name}` captures a single word.
+    pub fn parse(raw: &str) -> Result<Self, ConfigError> {
+        Self::parse_in(raw, &Pronunciations::default())
+    }
+
+    /// Like `parse`, but looks up the pronounciation of each word in `dict` before CMUdict.
+    pub fn parse_in(raw: &str, dict: &Pronunciations) -> Result<Self, ConfigError> {
+        let mut retvl = Self::default();
+        let mut literal = Vec::new();
+        for word in raw.split_whitespace() {
+            if !word.contains('{') && !word.contains('}') {
+                literal.push(word);
+                continue;
+            }
+            let (name, kind) = parse_slot(word)?;
+            retvl.push_literal(&literal.join(" "), dict);
+            literal.clear();
+            retvl.parts.push(MessagePart::Slot {
+                name: name.to_owned(),
+                kind,
+            });
+        }
+        retvl.push_literal(&literal.join(" "), dict);
+        Ok(retvl)
+    }
+
+    fn push_literal(&mut self, raw: &str, dict: &Pronunciations) {
+        if !raw.is_empty() {
+            let phones = Utterance::parse_with_unknowns_in(raw, dict);
+            self.push_part(MessagePart::Literal(phones));
+        }
+    }
+
+    /// Pushes a part onto the end of the pattern, merging neighbouring literals.
+    fn push_part(&mut self, part: MessagePart) {
+        match (self.parts.last_mut(), part) {
+            (Some(MessagePart::Literal(prev)), MessagePart::Literal(next)) => {
+                *prev = prev.join(&next);
+            }
+            (_, part) => self.parts.push(part),
+        }
+    }
+
+    pub fn parts(&self) -> &[MessagePart] {
+        &self.parts
+    }
+
+    /// Whether the pattern matches only silence.
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Constructs a new pattern consisting of `self` followed by `other`.
+    pub fn join(&self, other: &MessagePattern) -> Self {
+        let mut retvl = self.clone();
+        for part in other.parts.iter().cloned() {
+            retvl.push_part(part);
+        }
+        retvl
+    }
+}
+
+/// Parses a `{name:kind}` placeholder.
+fn parse_slot(word: &str) -> Result<(&str, SlotKind), ConfigError> {
+    let invalid = || ConfigError::InvalidSlot(word.to_owned());
+    let inner = word
+        .strip_prefix('{')
+        .and_then(|w| w.strip_suffix('}'))
+        .ok_or_else(invalid)?;
+    let (name, kind) = match inner.find(':') {
+        Some(idx) => (&inner[..idx], &inner[idx + 1..]),
+        None => (inner, "word"),
+    };
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_name {
+        return Err(invalid());
+    }
+    let kind = SlotKind::from_name(kind).ok_or_else(invalid)?;
+    Ok((name, kind))
+}
+
+/// A transcript split into words, along with the pronounciation of each word.
+pub struct SpokenWords<'a> {
+    words: Vec<&'a str>,
+    phones: Vec<Utterance>,
+}
+
+impl<'a> SpokenWords<'a> {
+    /// Splits `text` into words, looking up the pronounciation of each word in `dict` before CMUdict.
+    pub fn new_in(text: &'a str, dict: &Pronunciations) -> Self {
+        let words: Vec<_> = text.split_whitespace().collect();
+        let phones = words
+            .iter()
+            .map(|w| Utterance::parse_with_unknowns_in(w, dict))
+            .collect();
+        Self { words, phones }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// The words in the range `start..end`.
+    pub fn words(&self, start: usize, end: usize) -> &[&'a str] {
+        &self.words[start..end]
+    }
+
+    /// The pronounciation of the words in the range `start..end`.
+    pub fn phones(&self, start: usize, end: usize) -> Utterance {
+        self.phones[start..end]
+            .iter()
+            .fold(Utterance::default(), |acc, cur| acc.join(cur))
+    }
+}
+
+/// Parses a number spoken as words, like "one hundred and twenty five", or written as digits.
+pub fn parse_spoken_number(words: &[&str]) -> Option<u64> {
+    if let [word] = words {
+        if let Ok(n) = word.parse() {
+            return Some(n);
+        }
+        if word.eq_ignore_ascii_case("zero") {
+            return Some(0);
+        }
+    }
+
+    // The total so far, and the part below the last "thousand" or "million".
+    let mut total = 0u64;
+    let mut current = 0u64;
+
+    // The value of the last unit, teen, or tens word in `current`, to reject things like "five five".
+    let mut last_small: Option<u64> = None;
+    let mut prev_word = String::new();
+    for (idx, word) in words.iter().enumerate() {
+        let word = word.to_lowercase();
+        if let Some(value) = small_number(&word) {
+            let fits = match last_small {
+                None => true,
+                // "twenty five", but not "twenty fifteen" or "twenty thirty".
+                Some(prev) => prev >= 20 && prev % 10 == 0 && value < 10,
+            };
+            if !fits || value == 0 {
+                return None;
+            }
+            current += value;
+            last_small = Some(value);
+        } else if word == "hundred" {
+            if current == 0 || current >= 100 {
+                return None;
+            }
+            current *= 100;
+            last_small = None;
+        } else if let Some(scale) = scale_word(&word) {
+            if current == 0 {
+                return None;
+            }
+            total += current.checked_mul(scale)?;
+            current = 0;
+            last_small = None;
+        } else if word == "and" {
+            let after_scale = prev_word == "hundred" || scale_word(&prev_word).is_some();
+            if !after_scale || idx + 1 == words.len() {
+                return None;
+            }
+        } else {
+            return None;
+        }
+        prev_word = word;
+    }
+    if words.is_empty() {
+        None
+    } else {
+        Some(total + current)
+    }
+}
+
+fn small_number(word: &str) -> Option<u64> {
+    const UNITS: [&str; 20] = [
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+    const TENS: [&str; 8] = [
+        "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+    if let Some(idx) = UNITS.iter().position(|&w| w == word) {
+        return Some(idx as u64);
+    }
+    TENS.iter()
+        .position(|&w| w == word)
+        .map(|idx| (idx as u64 + 2) * 10)
+}
+
+fn scale_word(word: &str) -> Option<u64> {
+    match word {
+        "thousand" => Some(1_000),
+        "million" => Some(1_000_000),
+        "billion" => Some(1_000_000_000),
+        _ => None,
+    }
+}
+
+/// Replaces each `{name}` in `text` with the value captured by the slot `name`.
+///
+/// The text is read once from left to right, so a value that itself contains `{name}` is left as it is.
+pub fn substitute(text: &str, values: &SlotValues) -> String {
+    substitute_with(text, values, |value| value.to_owned())
+}
+
+/// Replaces each `{name}` in a shell command line like `substitute`, quoting every value so that
+/// the shell takes it as a single word, whatever is in it.
+pub fn substitute_quoted(command: &str, values: &SlotValues) -> String {
+    substitute_with(command, values, shell_quote)
+}
+
+fn substitute_with(text: &str, values: &SlotValues, quote: impl Fn(&str) -> String) -> String {
+    let mut retvl = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        retvl.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('}')
+            .and_then(|end| Some((end, values.get(&after[..end])?)));
+        match value {
+            Some((end, value)) => {
+                retvl.push_str(&quote(value));
+                rest = &after[end + 1..];
+            }
+            None => {
+                retvl.push('{');
+                rest = after;
+            }
+        }
+    }
+    retvl.push_str(rest);
+    retvl
+}
+
+/// Quotes `value` for a POSIX shell, like `'don'\''t'`.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// The environment variable a slot's value is passed to commands in, like `ASSISTANT_SLOT_LEVEL`.
+pub fn env_var_name(slot_name: &str) -> String {
+    format!(
+        "ASSISTANT_SLOT_{}",
+        slot_name.to_uppercase().replace('-', "_")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(text: &str) -> Option<u64> {
+        let words: Vec<_> = text.split_whitespace().collect();
+        parse_spoken_number(&words)
+    }
+
+    #[test]
+    fn test_parse_spoken_number() {
+        assert_eq!(number("zero"), Some(0));
+        assert_eq!(number("seven"), Some(7));
+        assert_eq!(number("fifteen"), Some(15));
+        assert_eq!(number("twenty five"), Some(25));
+        assert_eq!(number("one hundred"), Some(100));
+        assert_eq!(number("three hundred and twelve"), Some(312));
+        assert_eq!(number("two thousand and twenty"), Some(2020));
+        assert_eq!(number("one million two hundred thousand"), Some(1_200_000));
+        assert_eq!(number("42"), Some(42));
+
+        assert_eq!(number(""), None);
+        assert_eq!(number("five five"), None);
+        assert_eq!(number("twenty thirty"), None);
+        assert_eq!(number("twenty fifteen"), None);
+        assert_eq!(number("hundred"), None);
+        assert_eq!(number("five and"), None);
+        assert_eq!(number("fire"), None);
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        let pattern = MessagePattern::parse("set volume {level:number} now").unwrap();
+        assert_eq!(pattern.parts().len(), 3);
+        assert!(matches!(
+            pattern.parts()[1],
+            MessagePart::Slot {
+                kind: SlotKind::Number,
+                ..
+            }
+        ));
+
+        let pattern = MessagePattern::parse("open {site}").unwrap();
+        assert!(matches!(
+            pattern.parts()[1],
+            MessagePart::Slot {
+                kind: SlotKind::Word,
+                ..
+            }
+        ));
+
+        // Joined literals are merged.
+        let joined = MessagePattern::parse("fire fox")
+            .unwrap()
+            .join(&MessagePattern::parse("you tube").unwrap());
+        assert_eq!(joined, MessagePattern::parse("fire fox you tube").unwrap());
+
+        assert!(MessagePattern::parse("open {site:url}").is_err());
+        assert!(MessagePattern::parse("open {site").is_err());
+        assert!(MessagePattern::parse("open {:word}").is_err());
+        assert!(MessagePattern::parse("open x{site}").is_err());
+    }
+
+    #[test]
+    fn test_substitute() {
+        let mut values = SlotValues::new();
+        values.insert("level".to_owned(), "25".to_owned());
+        assert_eq!(
+            substitute("amixer set Master {level}% # {other}", &values),
+            "amixer set Master 25% # {other}"
+        );
+
+        // Values are never substituted into again, and are quoted for the shell.
+        values.insert("site".to_owned(), "{level}'; $(rm -rf ~)".to_owned());
+        assert_eq!(substitute("open {site}", &values), "open {level}'; $(rm -rf ~)");
+        assert_eq!(
+            substitute_quoted("open {site} {level}", &values),
+            r"open '{level}'\''; $(rm -rf ~)' '25'"
+        );
+        assert_eq!(env_var_name("site-name"), "ASSISTANT_SLOT_SITE_NAME");
+    }
+}
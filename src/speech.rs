@@ -1,17 +1,18 @@
-use crate::error::PhonemeConvertionError;
+use crate::error::{ConfigError, PhonemeConvertionError};
 use crate::utils::IterUtils;
 use arpabet::{phoneme::Phoneme, Arpabet};
+use std::collections::HashMap;
 
 
 /// Attempt to convert a string into its pronounciation. 
 ///
-/// Currently this can only be done on strings composed of words in the CMUdict database.
-fn conv<'a>(raw_msg: &'a str) -> impl Iterator<Item = PhonePart> + 'a {
-    let ap = Arpabet::load_cmudict();
+/// Words in `dict` or the CMUdict database use their pronounciation from there, and the rest are
+/// guessed from their spelling with `guess_pronunciation`.
+fn conv<'a>(raw_msg: &'a str, dict: &'a Pronunciations) -> impl Iterator<Item = PhonePart> + 'a {
     raw_msg
         .split_whitespace()
         .flat_map(move |w| {
-            let nxt = match ap.get_polyphone(w) {
+            let nxt = match dict.get(w).or_else(|| guess_pronunciation(w)) {
                 Some(pw) => pw.into_iter().map(|p| PhonePart::Phoneme(p)).left(),
                 None => std::iter::once(PhonePart::Unknown(w.to_owned())).right(),
             };
@@ -21,6 +22,345 @@ fn conv<'a>(raw_msg: &'a str) -> impl Iterator<Item = PhonePart> + 'a {
 }
 
 
+/// Parses a pronounciation written as ARPABET phonemes, like `HH EH0 L OW1`.
+pub fn parse_phonemes(raw: &str) -> Option<Vec<Phoneme>> {
+    let phones = raw
+        .split_whitespace()
+        .map(Phoneme::from_str)
+        .collect::<Option<Vec<_>>>()?;
+    if phones.is_empty() {
+        None
+    } else {
+        Some(phones)
+    }
+}
+
+/// Pronounciations of words that are missing from CMUdict, or that should be pronounced differently.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pronunciations {
+    words: HashMap<String, Vec<Phoneme>>,
+}
+
+impl Pronunciations {
+
+    /// Parses a dictionary in the CMUdict format, with a word and then its phonemes on each line.
+    ///
+    /// Comments starting with `;;;` or `#` are skipped, as are alternate pronounciations like `WORD(2)`.
+    pub fn parse_cmudict(raw: &str) -> Result<Self, ConfigError> {
+        let mut retvl = Self::default();
+        for line in raw.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() || line.starts_with(";;;") {
+                continue;
+            }
+            let (word, phones) = match line.find(char::is_whitespace) {
+                Some(idx) => (&line[..idx], &line[idx..]),
+                None => (line, ""),
+            };
+            if word.ends_with(')') {
+                continue;
+            }
+            let parsed = parse_phonemes(phones).ok_or_else(|| ConfigError::InvalidPronunciation {
+                word: word.to_owned(),
+                pronunciation: phones.trim().to_owned(),
+            })?;
+            retvl.insert(word, parsed);
+        }
+        Ok(retvl)
+    }
+
+    /// Adds the pronounciation of `word`, replacing any it already had.
+    pub fn insert(&mut self, word: &str, phones: Vec<Phoneme>) {
+        self.words.insert(word.to_lowercase(), phones);
+    }
+
+    /// Combines the words in `self` with `other`.
+    ///
+    /// If a word is in both `self` and `other`, the pronounciation in `self` is used.
+    pub fn or_else(mut self, other: Pronunciations) -> Self {
+        for (word, phones) in other.words {
+            self.words.entry(word).or_insert(phones);
+        }
+        self
+    }
+
+    /// Returns the pronounciation of `word`, preferring these pronounciations over CMUdict's.
+    ///
+    /// Returns `None` if the word is in neither, even if its pronounciation could be guessed.
+    pub fn get(&self, word: &str) -> Option<Vec<Phoneme>> {
+        match self.words.get(&word.to_lowercase()) {
+            Some(phones) => Some(phones.clone()),
+            None => Arpabet::load_cmudict().get_polyphone(word),
+        }
+    }
+}
+
+/// The pronounciation of each letter's name, for spelling words out.
+const LETTER_NAMES: [&str; 26] = [
+    "EY1", "B IY1", "S IY1", "D IY1", "IY1", "EH1 F", "JH IY1", "EY1 CH", "AY1", "JH EY1",
+    "K EY1", "EH1 L", "EH1 M", "EH1 N", "OW1", "P IY1", "K Y UW1", "AA1 R", "EH1 S", "T IY1",
+    "Y UW1", "V IY1", "D AH1 B AH0 L Y UW0", "EH1 K S", "W AY1", "Z IY1",
+];
+
+/// The pronounciation of each digit's name, for reading numbers out digit by digit.
+const DIGIT_NAMES: [&str; 10] = [
+    "Z IY1 R OW0", "W AH1 N", "T UW1", "TH R IY1", "F AO1 R", "F AY1 V", "S IH1 K S",
+    "S EH1 V AH0 N", "EY1 T", "N AY1 N",
+];
+
+/// The consonants that an English word can start with, including none at all.
+const ONSETS: &[&str] = &[
+    "", "b", "c", "d", "f", "g", "h", "j", "k", "l", "m", "n", "p", "q", "r", "s", "t", "v",
+    "w", "x", "y", "z", "bl", "br", "ch", "chr", "cl", "cr", "dr", "dw", "fl", "fr", "gh", "gl",
+    "gn", "gr", "kl", "kn", "kr", "ph", "phr", "pl", "pn", "pr", "ps", "sc", "sch", "scr", "sh",
+    "shr", "sk", "sl", "sm", "sn", "sp", "sph", "spl", "spr", "st", "str", "sw", "th", "thr",
+    "tr", "ts", "tw", "wh", "wr",
+];
+
+/// The ARPABET vowels, without their stress markers.
+const VOWEL_SYMBOLS: &[&str] = &[
+    "AA", "AE", "AH", "AO", "AW", "AY", "EH", "ER", "EY", "IH", "IY", "OW", "OY", "UH", "UW",
+];
+
+/// Guesses the pronounciation of a word that is in no dictionary, from the way English is
+/// usually spelled.
+///
+/// Numbers are read out digit by digit. Acronyms written in capitals, words without vowels, and
+/// leading letters that no English word starts with are spelled out, so "htop" sounds like "h top".
+/// Returns `None` if the word has anything but letters, digits and apostrophes in it.
+pub fn guess_pronunciation(word: &str) -> Option<Vec<Phoneme>> {
+    let word: String = word.chars().filter(|&c| c != '\'').collect();
+    if word.is_empty() || !word.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let mut retvl = Vec::new();
+    let mut rest = word.as_str();
+    while !rest.is_empty() {
+        let is_digit = rest.as_bytes()[0].is_ascii_digit();
+        let len = rest
+            .bytes()
+            .position(|b| b.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+        let (run, next) = rest.split_at(len);
+        if is_digit {
+            for b in run.bytes() {
+                push_phones(&mut retvl, DIGIT_NAMES[usize::from(b - b'0')]);
+            }
+        } else {
+            sound_out(run, &mut retvl);
+        }
+        rest = next;
+    }
+    Some(retvl)
+}
+
+fn push_phones(out: &mut Vec<Phoneme>, raw: &str) {
+    out.extend(raw.split_whitespace().filter_map(Phoneme::from_str));
+}
+
+/// Spells out `letters` one letter at a time.
+fn spell(letters: &[u8], out: &mut Vec<Phoneme>) {
+    for b in letters {
+        push_phones(out, LETTER_NAMES[usize::from(b.to_ascii_lowercase() - b'a')]);
+    }
+}
+
+/// Guesses the pronounciation of a run of letters, spelling out whatever cannot be sounded out.
+fn sound_out(run: &str, out: &mut Vec<Phoneme>) {
+    let is_acronym = run.len() > 1 && run.bytes().all(|b| b.is_ascii_uppercase());
+    let word = run.to_ascii_lowercase().into_bytes();
+    let first_vowel = (0..word.len()).find(|&idx| is_vowel_at(&word, idx));
+    let first_vowel = match first_vowel {
+        Some(idx) if !is_acronym && word.len() > 1 => idx,
+        _ => return spell(&word, out),
+    };
+
+    // Spell out leading letters until what is left starts like an English word.
+    let onset = &run[..first_vowel].to_ascii_lowercase();
+    let skip = (0..=onset.len())
+        .find(|&idx| ONSETS.contains(&&onset[idx..]))
+        .unwrap_or(first_vowel);
+    spell(&word[..skip], out);
+    letters_to_sounds(&word[skip..], out);
+}
+
+/// Whether the letter at `idx` is sounded as a vowel. A "y" is a vowel unless it starts a word or
+/// comes before another vowel.
+fn is_vowel_at(word: &[u8], idx: usize) -> bool {
+    match word[idx] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => true,
+        b'y' => idx > 0 && !matches!(word.get(idx + 1), Some(&b) if is_vowel_letter(b)),
+        _ => false,
+    }
+}
+
+fn is_vowel_letter(b: u8) -> bool {
+    matches!(b, b'a' | b'e' | b'i' | b'o' | b'u' | b'y')
+}
+
+/// Converts lowercase letters to phonemes, stressing the first vowel and reducing the rest.
+fn letters_to_sounds(word: &[u8], out: &mut Vec<Phoneme>) {
+    let mut sounds = Vec::new();
+    let mut idx = 0;
+    while idx < word.len() {
+        let has_vowel = sounds.iter().any(|s| VOWEL_SYMBOLS.contains(s));
+        let (symbols, len) = next_sound(word, idx, has_vowel);
+        sounds.extend_from_slice(symbols);
+        idx += len;
+    }
+    let mut stressed = false;
+    for symbol in sounds {
+        if !VOWEL_SYMBOLS.contains(&symbol) {
+            push_phones(out, symbol);
+        } else if !stressed {
+            stressed = true;
+            push_phones(out, &format!("{}1", symbol));
+        } else if matches!(symbol, "AA" | "AE" | "AH" | "EH") {
+            push_phones(out, "AH0");
+        } else {
+            push_phones(out, &format!("{}0", symbol));
+        }
+    }
+}
+
+/// Finds the sounds made by the letters at `idx`, along with how many letters make them.
+///
+/// Vowels are returned without their stress. `has_vowel` is whether a vowel has been sounded yet.
+fn next_sound(word: &[u8], idx: usize, has_vowel: bool) -> (&'static [&'static str], usize) {
+    let rest = &word[idx..];
+    let starts = |s: &str| rest.starts_with(s.as_bytes());
+    let at = |offset: usize| word.get(idx + offset).copied();
+    let ends_after = |len: usize| idx + len == word.len();
+    let is_consonant = |b: Option<u8>| matches!(b, Some(b) if !is_vowel_letter(b));
+
+    // Silent letters at the start of a word.
+    if idx == 0 {
+        if starts("kn") || starts("gn") || starts("pn") {
+            return (&["N"], 2);
+        } else if starts("wr") {
+            return (&["R"], 2);
+        } else if starts("ps") {
+            return (&["S"], 2);
+        }
+    }
+
+    // Vowels written with more than one letter.
+    let digraphs: &[(&str, &'static [&'static str])] = &[
+        ("igh", &["AY"]),
+        ("eau", &["OW"]),
+        ("ee", &["IY"]),
+        ("ea", &["IY"]),
+        ("oo", &["UW"]),
+        ("ou", &["AW"]),
+        ("ow", &["OW"]),
+        ("oi", &["OY"]),
+        ("oy", &["OY"]),
+        ("ai", &["EY"]),
+        ("ay", &["EY"]),
+        ("au", &["AO"]),
+        ("aw", &["AO"]),
+        ("ei", &["EY"]),
+        ("ey", if ends_after(2) { &["IY"] } else { &["EY"] }),
+        ("ie", &["IY"]),
+        ("ue", &["UW"]),
+        ("ew", &["UW"]),
+        ("ui", &["UW"]),
+        ("oa", &["OW"]),
+    ];
+    if let Some((letters, symbols)) = digraphs.iter().find(|(letters, _)| starts(letters)) {
+        return (symbols, letters.len());
+    }
+
+    if is_vowel_at(word, idx) {
+        let letter = word[idx];
+
+        // A vowel before an "r" that does not start the next syllable.
+        if at(1) == Some(b'r') && !matches!(at(2), Some(b) if is_vowel_letter(b)) {
+            return match letter {
+                b'a' => (&["AA", "R"], 2),
+                b'o' => (&["AO", "R"], 2),
+                _ => (&["ER"], 2),
+            };
+        }
+        if letter == b'e' && ends_after(1) && has_vowel {
+            return (&[], 1);
+        }
+        if letter == b'y' && ends_after(1) && has_vowel {
+            return (&["IY"], 1);
+        }
+
+        // A vowel is long at the end of a word, or before a consonant and a silent "e".
+        let is_long =
+            ends_after(1) || (is_consonant(at(1)) && at(2) == Some(b'e') && ends_after(3));
+        let symbol: &'static [&'static str] = match (letter, is_long) {
+            (b'a', true) => &["EY"],
+            (b'a', false) => &["AE"],
+            (b'e', true) => &["IY"],
+            (b'e', false) => &["EH"],
+            (b'i', true) | (b'y', true) => &["AY"],
+            (b'i', false) | (b'y', false) => &["IH"],
+            (b'o', true) => &["OW"],
+            (b'o', false) => &["AA"],
+            (b'u', true) => &["UW"],
+            _ => &["AH"],
+        };
+        return (symbol, 1);
+    }
+
+    // A doubled consonant is only sounded once.
+    if idx > 0 && word[idx - 1] == word[idx] {
+        return (&[], 1);
+    }
+    if starts("le") && ends_after(2) && has_vowel {
+        return (&["AH", "L"], 2);
+    }
+    let clusters: &[(&str, &'static [&'static str])] = &[
+        ("tch", &["CH"]),
+        ("sch", &["S", "K"]),
+        ("ch", &["CH"]),
+        ("sh", &["SH"]),
+        ("th", &["TH"]),
+        ("ph", &["F"]),
+        ("wh", &["W"]),
+        ("ck", &["K"]),
+        ("ng", &["NG"]),
+        ("qu", &["K", "W"]),
+        ("dg", &["JH"]),
+        ("gh", if idx == 0 { &["G"] } else { &[] }),
+    ];
+    if let Some((letters, symbols)) = clusters.iter().find(|(letters, _)| starts(letters)) {
+        return (symbols, letters.len());
+    }
+    let softened = matches!(at(1), Some(b'e') | Some(b'i') | Some(b'y'));
+    let symbol: &'static [&'static str] = match word[idx] {
+        b'b' => &["B"],
+        b'c' if softened => &["S"],
+        b'c' | b'k' | b'q' => &["K"],
+        b'd' => &["D"],
+        b'f' => &["F"],
+        b'g' if softened => &["JH"],
+        b'g' => &["G"],
+        b'h' => &["HH"],
+        b'j' => &["JH"],
+        b'l' => &["L"],
+        b'm' => &["M"],
+        b'n' => &["N"],
+        b'p' => &["P"],
+        b'r' => &["R"],
+        b's' => &["S"],
+        b't' => &["T"],
+        b'v' => &["V"],
+        b'w' => &["W"],
+        b'x' if idx == 0 => &["Z"],
+        b'x' => &["K", "S"],
+        b'y' => &["Y"],
+        b'z' => &["Z"],
+        _ => &[],
+    };
+    (symbol, 1)
+}
+
+
 /// A pronounciation unit in an audio transcript.
 #[derive(PartialEq, Debug, Clone)]
 pub enum PhonePart {
@@ -31,11 +371,11 @@ pub enum PhonePart {
     /// A divider between words in a transcript.
     Space,
 
-    /// A words whose pronounciation cannot (yet) be found.
+    /// A words whose pronounciation cannot be found or guessed, like one with symbols in it.
     Unknown(String),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Utterance {
     phones: Vec<PhonePart>,
 }
@@ -43,10 +383,15 @@ pub struct Utterance {
 impl Utterance {
 
     /// Attemts to parse a string into its pronounciation, erroring if `raw_msg` contains a word
-    /// whose pronounciation cannot be found.
+    /// whose pronounciation cannot be found or guessed.
     #[allow(unused)]
     pub fn parse(raw_msg: &str) -> Result<Self, PhonemeConvertionError> {
-        let phones = conv(raw_msg)
+        Self::parse_in(raw_msg, &Pronunciations::default())
+    }
+
+    /// Like `parse`, but looks words up in `dict` before CMUdict.
+    pub fn parse_in(raw_msg: &str, dict: &Pronunciations) -> Result<Self, PhonemeConvertionError> {
+        let phones = conv(raw_msg, dict)
             .map(|p| match p {
                 PhonePart::Unknown(inner) => Err(PhonemeConvertionError { raw: inner }),
                 other => Ok(other),
@@ -56,10 +401,84 @@ impl Utterance {
     }
     
     /// Attemts to parse a string into its pronounciation, returning a `PhonePart::Unknown` for words
-    /// whose pronounciation cannot yet be found.
+    /// whose pronounciation cannot be found or guessed.
     #[allow(unused)]
     pub fn parse_with_unknowns(raw_msg: &str) -> Self {
-        let phones = conv(raw_msg).collect();
+        Self::parse_with_unknowns_in(raw_msg, &Pronunciations::default())
+    }
+
+    /// Like `parse_with_unknowns`, but looks words up in `dict` before CMUdict.
+    pub fn parse_with_unknowns_in(raw_msg: &str, dict: &Pronunciations) -> Self {
+        let phones = conv(raw_msg, dict).collect();
+        Self { phones }
+    }
+
+    /// The pronounciation units making up this utterance.
+    pub fn phones(&self) -> &[PhonePart] {
+        &self.phones
+    }
+
+    /// Constructs a new utterance consisting of `self` followed by `other`.
+    pub fn join(&self, other: &Utterance) -> Self {
+        let mut phones = Vec::with_capacity(self.phones.len() + other.phones.len() + 1);
+        phones.extend_from_slice(&self.phones);
+        if !self.phones.is_empty() && !other.phones.is_empty() {
+            phones.push(PhonePart::Space);
+        }
+        phones.extend_from_slice(&other.phones);
         Self { phones }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phonemes(raw: &str) -> Vec<Phoneme> {
+        parse_phonemes(raw).unwrap()
+    }
+
+    #[test]
+    fn test_guess_pronunciation() {
+        let guess = |word| guess_pronunciation(word).unwrap();
+        assert_eq!(guess("telegram"), phonemes("T EH1 L AH0 G R AH0 M"));
+        assert_eq!(guess("zorblax"), phonemes("Z AO1 R B L AH0 K S"));
+        assert_eq!(guess("zope"), phonemes("Z OW1 P"));
+
+        // Acronyms, and letters that no word starts with, are spelled out.
+        assert_eq!(guess("htop"), phonemes("EY1 CH T AA1 P"));
+        assert_eq!(guess("HTML"), phonemes("EY1 CH T IY1 EH1 M EH1 L"));
+        assert_eq!(guess("vlc"), phonemes("V IY1 EH1 L S IY1"));
+        assert_eq!(guess("mp3"), phonemes("EH1 M P IY1 TH R IY1"));
+        assert_eq!(guess_pronunciation("a#b"), None);
+
+        // Guesses fill in for words missing from the dictionary.
+        let h_top = Utterance::parse("h top").unwrap();
+        let mut expected: Vec<_> = phonemes("EY1 CH").into_iter().map(PhonePart::Phoneme).collect();
+        expected.push(PhonePart::Space);
+        expected.extend(phonemes("T AA1 P").into_iter().map(PhonePart::Phoneme));
+        assert_eq!(h_top.phones(), &expected[..]);
+        assert!(Utterance::parse("open zorblax").is_ok());
+        assert!(Utterance::parse("open a#b").is_err());
+    }
+
+    #[test]
+    fn test_parse_cmudict() {
+        let raw = ";;; Extra words\n\
+            HTOP  EY1 CH T AA1 P\n\
+            HTOP(2)  HH T AA1 P\n\
+            fox  F AO1 K S # Not like CMUdict\n";
+        let dict = Pronunciations::parse_cmudict(raw).unwrap();
+        assert_eq!(dict.get("htop"), Some(phonemes("EY1 CH T AA1 P")));
+        assert_eq!(dict.get("FOX"), Some(phonemes("F AO1 K S")));
+        assert_eq!(dict.get("fire"), Some(phonemes("F AY1 ER0")));
+        assert_eq!(dict.get("zorblax"), None);
+
+        let mut first = Pronunciations::default();
+        first.insert("Fox", phonemes("F AA1 K S"));
+        assert_eq!(first.or_else(dict).get("fox"), Some(phonemes("F AA1 K S")));
+
+        assert!(Pronunciations::parse_cmudict("HTOP  EY1 CH TOP").is_err());
+        assert!(Pronunciations::parse_cmudict("HTOP").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! Fakes for testing the listen → match → run pipeline without a microphone or DeepSpeech model.
+
+use crate::buffer::AudioSource;
+use crate::error::AssistantRsError;
+use crate::feedback::{Cue, CuePlayer};
+use crate::recognizer::{RecognizerStream, SpeechRecognizer};
+use crate::tts::TtsSink;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The transcript a `ScriptedRecognizer` reports over the course of a single utterance,
+/// as a list of `(samples fed, transcript)` pairs.
+///
+/// Once at least `samples fed` samples have been fed to the stream, its transcript becomes `transcript`.
+pub type Script = Vec<(usize, String)>;
+
+/// A `SpeechRecognizer` that replays a series of scripted transcripts, one per stream.
+///
+/// Streams created after the scripts run out never hear anything.
+pub struct ScriptedRecognizer {
+    sample_rate: u32,
+    scripts: VecDeque<Script>,
+}
+
+impl ScriptedRecognizer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            scripts: VecDeque::new(),
+        }
+    }
+
+    /// Adds a script for the next utterance.
+    pub fn with_script(mut self, script: Script) -> Self {
+        self.scripts.push_back(script);
+        self
+    }
+
+    /// Adds an utterance whose transcript becomes `text` after `at_sample` samples and never changes again.
+    pub fn with_utterance(self, at_sample: usize, text: &str) -> Self {
+        self.with_script(vec![(at_sample, text.to_owned())])
+    }
+}
+
+impl SpeechRecognizer for ScriptedRecognizer {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn create_stream(&mut self) -> Result<Box<dyn RecognizerStream>, AssistantRsError> {
+        let script = self.scripts.pop_front().unwrap_or_default();
+        Ok(Box::new(ScriptedStream {
+            script,
+            samples_fed: 0,
+        }))
+    }
+}
+
+struct ScriptedStream {
+    script: Script,
+    samples_fed: usize,
+}
+
+impl ScriptedStream {
+    fn current_text(&self) -> String {
+        self.script
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= self.samples_fed)
+            .map(|(_, text)| text.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl RecognizerStream for ScriptedStream {
+    fn feed_audio(&mut self, data: &[i16]) {
+        self.samples_fed += data.len();
+    }
+
+    fn intermediate_decode(&mut self) -> Result<String, AssistantRsError> {
+        Ok(self.current_text())
+    }
+
+    fn finish(self: Box<Self>) -> Result<String, AssistantRsError> {
+        Ok(self.current_text())
+    }
+}
+
+/// An `AudioSource` that produces a fixed number of silent samples before running out.
+pub struct SilentSource {
+    samples_left: usize,
+}
+
+impl SilentSource {
+    pub fn new(samples: usize) -> Self {
+        Self {
+            samples_left: samples,
+        }
+    }
+}
+
+impl AudioSource for SilentSource {
+    fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError> {
+        let count = target.min(self.samples_left);
+        self.samples_left -= count;
+        Ok(vec![0; count])
+    }
+}
+
+/// An `AudioSource` that produces a fixed list of samples before running out.
+pub struct SampleSource {
+    samples: Vec<i16>,
+    position: usize,
+}
+
+impl SampleSource {
+    pub fn new(samples: Vec<i16>) -> Self {
+        Self {
+            samples,
+            position: 0,
+        }
+    }
+}
+
+impl AudioSource for SampleSource {
+    fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError> {
+        let end = (self.position + target).min(self.samples.len());
+        let retvl = self.samples[self.position..end].to_vec();
+        self.position = end;
+        Ok(retvl)
+    }
+}
+
+/// A `CuePlayer` that records the cues it plays instead of making any sound.
+///
+/// Clones share the same record, so a test can keep one while the context owns another.
+#[derive(Clone, Default)]
+pub struct RecordingCuePlayer {
+    played: Arc<Mutex<Vec<Cue>>>,
+}
+
+impl RecordingCuePlayer {
+    /// The cues played so far, in order.
+    pub fn played(&self) -> Vec<Cue> {
+        self.played.lock().unwrap().clone()
+    }
+}
+
+impl CuePlayer for RecordingCuePlayer {
+    fn play(&mut self, cue: Cue) -> Result<(), AssistantRsError> {
+        self.played.lock().unwrap().push(cue);
+        Ok(())
+    }
+}
+
+/// A `TtsSink` that records what it is asked to say instead of speaking.
+///
+/// Clones share the same record, so a test can keep one while the context owns another.
+#[derive(Clone, Default)]
+pub struct RecordingTtsSink {
+    said: Arc<Mutex<Vec<String>>>,
+}
+
+impl RecordingTtsSink {
+    /// The text said so far, in order.
+    pub fn said(&self) -> Vec<String> {
+        self.said.lock().unwrap().clone()
+    }
+}
+
+impl TtsSink for RecordingTtsSink {
+    fn say(&mut self, text: &str) -> Result<(), AssistantRsError> {
+        self.said.lock().unwrap().push(text.to_owned());
+        Ok(())
+    }
+}
+
+/// Generates a loud 200Hz tone at a 16kHz sample rate, loud enough to be mistaken for speech.
+pub fn tone(len: usize) -> Vec<i16> {
+    (0..len)
+        .map(|n| {
+            let phase = n as f64 * 2.0 * std::f64::consts::PI * 200.0 / 16000.0;
+            (5000.0 * phase.sin()) as i16
+        })
+        .collect()
+}
+
+/// A path in the temp directory for a test to use, unique to this process, with any file left
+/// there by an earlier run removed.
+pub fn temp_path(name: &str) -> PathBuf {
+    let mut retvl = std::env::temp_dir();
+    retvl.push(format!("assistant-rs-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&retvl);
+    retvl
+}
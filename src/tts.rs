@@ -0,0 +1,105 @@
+use crate::config::TtsConfig;
+use crate::error::AssistantRsError;
+use crate::exec::{self, CommandLine, ExecPolicy};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Speaks text to whoever is using the assistant.
+pub trait TtsSink {
+    /// Speaks `text`, returning once it has been spoken so that it is not heard by the microphone.
+    fn say(&mut self, text: &str) -> Result<(), AssistantRsError>;
+
+    /// Whether speaking makes any sound that the microphone could pick up.
+    fn is_audible(&self) -> bool {
+        true
+    }
+}
+
+/// A `TtsSink` for when there is no `tts-command`, which only logs what would have been said.
+pub struct NullTtsSink;
+
+impl TtsSink for NullTtsSink {
+    fn say(&mut self, text: &str) -> Result<(), AssistantRsError> {
+        log::log!(
+            log::Level::Info,
+            "Not speaking without a tts-command: {}",
+            text
+        );
+        Ok(())
+    }
+
+    fn is_audible(&self) -> bool {
+        false
+    }
+}
+
+/// Speaks by running a command, like `espeak -`, with the text on its standard input.
+pub struct CommandTtsSink {
+    line: CommandLine,
+    policy: ExecPolicy,
+}
+
+impl CommandTtsSink {
+    /// Creates a sink that runs `line`, killing it if it is still speaking after `timeout`.
+    pub fn new(line: CommandLine, timeout: Duration) -> Self {
+        let policy = ExecPolicy {
+            wait: Some(true),
+            timeout_ms: Some(timeout.as_millis() as u64),
+            ..ExecPolicy::default()
+        };
+        Self { line, policy }
+    }
+}
+
+impl TtsSink for CommandTtsSink {
+    fn say(&mut self, text: &str) -> Result<(), AssistantRsError> {
+        log::log!(log::Level::Debug, "Saying: {}", text);
+        exec::run_command_with_input(&self.line, &self.policy, &BTreeMap::new(), Some(text))?;
+        Ok(())
+    }
+}
+
+/// Builds the sink that speaks with the config's `tts-command`, or a silent one if there is none.
+pub fn build_tts_sink(conf: &TtsConfig) -> Box<dyn TtsSink> {
+    match conf.tts_command.as_ref() {
+        Some(line) => Box::new(CommandTtsSink::new(line.clone(), conf.tts_timeout())),
+        None => Box::new(NullTtsSink),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    #[test]
+    fn test_command_tts_sink() {
+        let path = temp_path("tts");
+        let line = CommandLine::Argv(vec![
+            "sh".to_owned(),
+            "-c".to_owned(),
+            "cat > \"$0\"".to_owned(),
+            path.to_string_lossy().into_owned(),
+        ]);
+        let mut sink = CommandTtsSink::new(line, TtsConfig::default().tts_timeout());
+
+        // The command has finished by the time `say` returns.
+        sink.say("opening firefox").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "opening firefox");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_command_tts_sink_timeout() {
+        let line = CommandLine::Shell("sleep 10".to_owned());
+        let mut sink = CommandTtsSink::new(line, Duration::from_millis(100));
+
+        // A command that never finishes speaking is killed rather than stalling the run, even
+        // with more text than fits in the pipe that it never reads.
+        let start = std::time::Instant::now();
+        sink.say("opening firefox").unwrap();
+        sink.say(&"opening firefox ".repeat(100_000)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::buffer::samples_to_duration;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The length of the audio frames that speech is detected over.
+const FRAME_MS: u32 = 20;
+
+/// The number of consecutive voiced frames needed before speech is considered to have started.
+const START_FRAMES: usize = 3;
+
+/// The lowest, and initial, noise energy assumed, so that digital silence does not make every
+/// sound count as speech.
+const MIN_NOISE_ENERGY: f64 = 900.0;
+
+/// How quickly the noise floor follows the energy of non-speech frames.
+const NOISE_ADAPT_RATE: f64 = 0.05;
+
+/// Frames crossing zero at least this often are treated as unvoiced consonants, like "s" or "f",
+/// which are much quieter than vowels.
+const FRICATIVE_ZCR: f64 = 0.3;
+
+/// Detects the start and end of speech based on the energy and zero-crossing rate of audio frames,
+/// compared against an adaptive estimate of the background noise.
+pub struct VoiceDetector {
+    sample_rate: u32,
+    frame_len: usize,
+    threshold_ratio: f64,
+    noise_floor: f64,
+
+    /// Samples that have not yet filled a complete frame.
+    pending: Vec<i16>,
+
+    /// The most recent audio from before speech started, so that the first syllable is not clipped.
+    preroll: VecDeque<i16>,
+    preroll_len: usize,
+
+    in_speech: bool,
+    voiced_run: usize,
+    samples_since_voiced: usize,
+}
+
+impl VoiceDetector {
+    /// Constructs a new `VoiceDetector`.
+    ///
+    /// A frame is considered voiced if its energy is at least `threshold_db` decibels above the noise floor.
+    /// Up to `preroll` of audio from before speech is detected is kept and returned along with the speech.
+    pub fn new(sample_rate: u32, threshold_db: u32, preroll: Duration) -> Self {
+        let frame_len = (sample_rate * FRAME_MS / 1000).max(1) as usize;
+        let preroll_len = (u64::from(sample_rate) * preroll.as_millis() as u64 / 1000) as usize;
+        Self {
+            sample_rate,
+            frame_len,
+            threshold_ratio: 10f64.powf(f64::from(threshold_db) / 10.0),
+            noise_floor: MIN_NOISE_ENERGY,
+            pending: Vec::with_capacity(frame_len),
+            preroll: VecDeque::with_capacity(preroll_len + START_FRAMES * frame_len),
+            preroll_len,
+            in_speech: false,
+            voiced_run: 0,
+            samples_since_voiced: 0,
+        }
+    }
+
+    /// Processes new audio, returning the samples that should be fed to the recognizer.
+    ///
+    /// Nothing is returned until speech starts, at which point the pre-roll audio is returned
+    /// as well.
+    pub fn process(&mut self, data: &[i16]) -> Vec<i16> {
+        let mut retvl = Vec::new();
+        self.pending.extend_from_slice(data);
+        let full_frames = self.pending.len() / self.frame_len * self.frame_len;
+        let frames: Vec<i16> = self.pending.drain(..full_frames).collect();
+        for frame in frames.chunks_exact(self.frame_len) {
+            self.process_frame(frame, &mut retvl);
+        }
+        retvl
+    }
+
+    fn process_frame(&mut self, frame: &[i16], output: &mut Vec<i16>) {
+        let is_voiced = self.classify(frame);
+        if self.in_speech {
+            output.extend_from_slice(frame);
+            if is_voiced {
+                self.samples_since_voiced = 0;
+            } else {
+                self.samples_since_voiced += frame.len();
+            }
+            return;
+        }
+
+        self.preroll.extend(frame.iter().copied());
+        // Keep the pre-roll plus the voiced frames that might start the speech.
+        let max_len = self.preroll_len + START_FRAMES * frame.len();
+        let excess = self.preroll.len().saturating_sub(max_len);
+        self.preroll.drain(..excess);
+        self.voiced_run = if is_voiced { self.voiced_run + 1 } else { 0 };
+        if self.voiced_run >= START_FRAMES {
+            self.in_speech = true;
+            self.samples_since_voiced = 0;
+            output.extend(self.preroll.drain(..));
+        }
+    }
+
+    /// Decides whether a frame contains speech, updating the noise floor if it does not.
+    fn classify(&mut self, frame: &[i16]) -> bool {
+        let energy = frame_energy(frame);
+        let zcr = zero_crossing_rate(frame);
+        let threshold = self.noise_floor * self.threshold_ratio;
+        let is_voiced = energy >= threshold || (zcr >= FRICATIVE_ZCR && energy >= threshold / 2.0);
+        if !is_voiced {
+            let next_floor = if energy < self.noise_floor {
+                energy
+            } else {
+                self.noise_floor + (energy - self.noise_floor) * NOISE_ADAPT_RATE
+            };
+            self.noise_floor = next_floor.max(MIN_NOISE_ENERGY);
+        }
+        is_voiced
+    }
+
+    /// Whether the detector has heard the start of speech.
+    pub fn speech_started(&self) -> bool {
+        self.in_speech
+    }
+
+    /// The time since the last voiced frame, if speech has started.
+    pub fn time_since_speech(&self) -> Duration {
+        samples_to_duration(self.samples_since_voiced, self.sample_rate)
+    }
+
+    /// Returns to waiting for speech to start, keeping the current noise floor estimate.
+    pub fn reset(&mut self) {
+        self.in_speech = false;
+        self.voiced_run = 0;
+        self.samples_since_voiced = 0;
+        self.preroll.clear();
+    }
+}
+
+/// The mean squared amplitude of the frame.
+fn frame_energy(frame: &[i16]) -> f64 {
+    let total: f64 = frame.iter().map(|&s| f64::from(s) * f64::from(s)).sum();
+    total / frame.len().max(1) as f64
+}
+
+/// The fraction of adjacent samples in the frame that differ in sign.
+fn zero_crossing_rate(frame: &[i16]) -> f64 {
+    let crossings = frame
+        .windows(2)
+        .filter(|w| (w[0] >= 0) != (w[1] >= 0))
+        .count();
+    crossings as f64 / frame.len().max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tone;
+
+    fn noise(len: usize, amplitude: i16) -> Vec<i16> {
+        // A cheap deterministic pseudo-random sequence.
+        let mut state = 12345u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as i16 % amplitude.max(1)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_vad_start_and_end() {
+        let mut vad = VoiceDetector::new(16000, 10, Duration::from_millis(100));
+
+        // Background noise alone never starts speech.
+        assert!(vad.process(&noise(16000, 50)).is_empty());
+        assert!(!vad.speech_started());
+
+        // Speech starts, and comes with the pre-roll.
+        let speech = tone(8000);
+        let fed = vad.process(&speech);
+        assert!(vad.speech_started());
+        assert_eq!(fed.len(), 8000 + 1600);
+        assert_eq!(vad.time_since_speech(), Duration::from_millis(0));
+
+        // Silence after the speech ends it.
+        let fed = vad.process(&noise(8000, 50));
+        assert_eq!(fed.len(), 8000);
+        assert_eq!(vad.time_since_speech(), Duration::from_millis(500));
+
+        vad.reset();
+        assert!(!vad.speech_started());
+        assert!(vad.process(&noise(1600, 50)).is_empty());
+    }
+
+    #[test]
+    fn test_vad_partial_frames() {
+        let mut vad = VoiceDetector::new(16000, 10, Duration::from_millis(0));
+        assert!(vad.process(&noise(3200, 50)).is_empty());
+        let speech = tone(3200);
+        let fed: Vec<i16> = speech.chunks(7).flat_map(|c| vad.process(c)).collect();
+        assert!(vad.speech_started());
+        assert_eq!(fed.len(), 3200);
+    }
+}
@@ -0,0 +1,319 @@
+use crate::buffer::AudioSource;
+use crate::error::{AssistantRsError, WavError};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// The longest `fmt ` chunk accepted, well past the 40 bytes of `WAVE_FORMAT_EXTENSIBLE`.
+const MAX_FORMAT_LEN: usize = 64;
+
+/// The `SubFormat` of a `WAVE_FORMAT_EXTENSIBLE` file holding PCM audio, `KSDATAFORMAT_SUBTYPE_PCM`.
+const SUBTYPE_PCM: [u8; 16] = [
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Streams mono, 16-bit PCM audio out of a WAV file.
+pub struct WavFileSource<R: Read> {
+    reader: R,
+    samples_left: usize,
+    sample_rate: u32,
+}
+
+impl WavFileSource<BufReader<File>> {
+    /// Opens the WAV file at `path`, verifying that it contains audio sampled at `sample_rate`.
+    pub fn open(path: impl AsRef<Path>, sample_rate: u32) -> Result<Self, WavError> {
+        let fh = File::open(path)?;
+        Self::new(BufReader::new(fh), sample_rate)
+    }
+
+    /// Opens the WAV file at `path`, whatever its sample rate.
+    pub fn open_any_rate(path: impl AsRef<Path>) -> Result<Self, WavError> {
+        let fh = File::open(path)?;
+        Self::read_header(BufReader::new(fh))
+    }
+}
+
+impl<R: Read> WavFileSource<R> {
+    /// Reads the WAV headers out of `reader`, leaving it positioned at the start of the sample data.
+    ///
+    /// Fails if the audio is not sampled at `sample_rate`.
+    pub fn new(reader: R, sample_rate: u32) -> Result<Self, WavError> {
+        let retvl = Self::read_header(reader)?;
+        if retvl.sample_rate != sample_rate {
+            return Err(WavError::SampleRateMismatch {
+                found: retvl.sample_rate,
+                expected: sample_rate,
+            });
+        }
+        Ok(retvl)
+    }
+
+    /// The sample rate of the audio in the file.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Reads all of the remaining samples.
+    pub fn read_all(&mut self) -> Result<Vec<i16>, WavError> {
+        // The length in the header is not trusted for allocating, since streamed files often
+        // leave it at its maximum.
+        let mut retvl = Vec::new();
+        loop {
+            let chunk = self.read_samples(self.sample_rate as usize)?;
+            if chunk.is_empty() {
+                break;
+            }
+            retvl.extend(chunk);
+        }
+        Ok(retvl)
+    }
+
+    fn read_header(mut reader: R) -> Result<Self, WavError> {
+        let mut riff_header = [0u8; 12];
+        reader.read_exact(&mut riff_header)?;
+        if &riff_header[0..4] != b"RIFF" || &riff_header[8..12] != b"WAVE" {
+            return Err(WavError::NotWave);
+        }
+        let mut sample_rate = None;
+        loop {
+            let mut chunk_header = [0u8; 8];
+            reader.read_exact(&mut chunk_header)?;
+            let chunk_len = read_u32(&chunk_header[4..8]) as usize;
+            match &chunk_header[0..4] {
+                b"fmt " => {
+                    // Check the length before allocating, since a corrupt header can claim anything.
+                    if chunk_len > MAX_FORMAT_LEN {
+                        return Err(WavError::UnsupportedFormat(format!(
+                            "fmt chunk is {} bytes long",
+                            chunk_len
+                        )));
+                    }
+                    let mut format = vec![0u8; chunk_len + chunk_len % 2];
+                    reader.read_exact(&mut format)?;
+                    sample_rate = Some(verify_format(&format)?);
+                }
+                b"data" => {
+                    let sample_rate = sample_rate.ok_or_else(|| {
+                        WavError::UnsupportedFormat("data chunk found before fmt chunk".to_owned())
+                    })?;
+                    let retvl = Self {
+                        reader,
+                        samples_left: chunk_len / 2,
+                        sample_rate,
+                    };
+                    return Ok(retvl);
+                }
+                _ => {
+                    // Chunks are padded to an even length.
+                    let to_skip = (chunk_len + chunk_len % 2) as u64;
+                    io::copy(&mut (&mut reader).take(to_skip), &mut io::sink())?;
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> AudioSource for WavFileSource<R> {
+    fn wait_until(&mut self, target: usize) -> Result<Vec<i16>, AssistantRsError> {
+        Ok(self.read_samples(target)?)
+    }
+}
+
+impl<R: Read> WavFileSource<R> {
+    /// Reads up to `target` samples, returning none once the audio runs out.
+    fn read_samples(&mut self, target: usize) -> Result<Vec<i16>, WavError> {
+        let count = target.min(self.samples_left);
+        let mut raw = vec![0u8; count * 2];
+        let mut filled = 0;
+        while filled < raw.len() {
+            let read = self.reader.read(&mut raw[filled..])?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        if filled < raw.len() {
+            // The file was truncated; treat what we got as the end of the audio.
+            self.samples_left = 0;
+        } else {
+            self.samples_left -= count;
+        }
+        let samples = raw[..filled - filled % 2]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        Ok(samples)
+    }
+}
+
+/// Verifies that the audio is mono, 16-bit PCM, returning its sample rate.
+fn verify_format(format: &[u8]) -> Result<u32, WavError> {
+    if format.len() < 16 {
+        return Err(WavError::UnsupportedFormat(
+            "fmt chunk is too short".to_owned(),
+        ));
+    }
+    let format_tag = read_u16(&format[0..2]);
+    let channels = read_u16(&format[2..4]);
+    let file_rate = read_u32(&format[4..8]);
+    let bits_per_sample = read_u16(&format[14..16]);
+    if format_tag != WAVE_FORMAT_PCM && format_tag != WAVE_FORMAT_EXTENSIBLE {
+        return Err(WavError::UnsupportedFormat(format!(
+            "format tag {:#x} is not PCM",
+            format_tag
+        )));
+    }
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        // The real format is in the extension, after the two byte extension size and six bytes
+        // of valid bits and channel mask.
+        if format.get(24..40) != Some(&SUBTYPE_PCM[..]) {
+            return Err(WavError::UnsupportedFormat(
+                "extensible format is not PCM".to_owned(),
+            ));
+        }
+    }
+    if file_rate == 0 {
+        return Err(WavError::UnsupportedFormat(
+            "sample rate is zero".to_owned(),
+        ));
+    }
+    if channels != 1 {
+        return Err(WavError::UnsupportedFormat(format!(
+            "expected mono audio, found {} channels",
+            channels
+        )));
+    }
+    if bits_per_sample != 16 {
+        return Err(WavError::UnsupportedFormat(format!(
+            "expected 16-bit samples, found {}-bit",
+            bits_per_sample
+        )));
+    }
+    Ok(file_rate)
+}
+
+fn read_u16(raw: &[u8]) -> u16 {
+    u16::from_le_bytes([raw[0], raw[1]])
+}
+
+fn read_u32(raw: &[u8]) -> u32 {
+    u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn build_wav(channels: u16, sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+        build_wav_with_format(
+            &build_format(WAVE_FORMAT_PCM, channels, sample_rate),
+            samples,
+        )
+    }
+
+    fn build_format(format_tag: u16, channels: u16, sample_rate: u32) -> Vec<u8> {
+        let mut retvl = Vec::new();
+        retvl.extend_from_slice(&format_tag.to_le_bytes());
+        retvl.extend_from_slice(&channels.to_le_bytes());
+        retvl.extend_from_slice(&sample_rate.to_le_bytes());
+        retvl.extend_from_slice(&(sample_rate * 2 * u32::from(channels)).to_le_bytes());
+        retvl.extend_from_slice(&(2 * channels).to_le_bytes());
+        retvl.extend_from_slice(&16u16.to_le_bytes());
+        retvl
+    }
+
+    fn build_extensible_wav(subformat: [u8; 16], samples: &[i16]) -> Vec<u8> {
+        let mut format = build_format(WAVE_FORMAT_EXTENSIBLE, 1, 16000);
+        format.extend_from_slice(&22u16.to_le_bytes());
+        format.extend_from_slice(&16u16.to_le_bytes());
+        format.extend_from_slice(&4u32.to_le_bytes());
+        format.extend_from_slice(&subformat);
+        build_wav_with_format(&format, samples)
+    }
+
+    fn build_wav_with_format(format: &[u8], samples: &[i16]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut retvl = Vec::new();
+        retvl.extend_from_slice(b"RIFF");
+        retvl.extend_from_slice(&(4 + 8 + format.len() as u32 + 12 + 8 + data_len).to_le_bytes());
+        retvl.extend_from_slice(b"WAVE");
+        retvl.extend_from_slice(b"fmt ");
+        retvl.extend_from_slice(&(format.len() as u32).to_le_bytes());
+        retvl.extend_from_slice(format);
+        retvl.extend_from_slice(b"LIST");
+        retvl.extend_from_slice(&3u32.to_le_bytes());
+        retvl.extend_from_slice(&[1, 2, 3, 0]);
+        retvl.extend_from_slice(b"data");
+        retvl.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            retvl.extend_from_slice(&s.to_le_bytes());
+        }
+        retvl
+    }
+
+    #[test]
+    fn test_wav_stream() {
+        let samples: Vec<i16> = (0..10).map(|n| n * 1000 - 5000).collect();
+        let raw = build_wav(1, 16000, &samples);
+        let mut source = WavFileSource::new(Cursor::new(raw), 16000).unwrap();
+        assert_eq!(source.wait_until(4).unwrap(), &samples[0..4]);
+        assert_eq!(source.wait_until(4).unwrap(), &samples[4..8]);
+        assert_eq!(source.wait_until(4).unwrap(), &samples[8..10]);
+        assert!(source.wait_until(4).unwrap().is_empty());
+
+        // A streamed file that never filled in its data length is read until it runs out.
+        let mut raw = build_wav(1, 16000, &samples);
+        let data_len_at = raw.len() - samples.len() * 2 - 4;
+        raw[data_len_at..data_len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut source = WavFileSource::new(Cursor::new(raw), 16000).unwrap();
+        assert_eq!(source.read_all().unwrap(), samples);
+    }
+
+    #[test]
+    fn test_wav_format_checks() {
+        let stereo = build_wav(2, 16000, &[0, 0]);
+        assert!(matches!(
+            WavFileSource::new(Cursor::new(stereo), 16000),
+            Err(WavError::UnsupportedFormat(_))
+        ));
+        let no_rate = build_wav(1, 0, &[0, 0]);
+        assert!(matches!(
+            WavFileSource::read_header(Cursor::new(no_rate)),
+            Err(WavError::UnsupportedFormat(_))
+        ));
+        let wrong_rate = build_wav(1, 44100, &[0, 0]);
+        assert!(matches!(
+            WavFileSource::new(Cursor::new(wrong_rate), 16000),
+            Err(WavError::SampleRateMismatch {
+                found: 44100,
+                expected: 16000
+            })
+        ));
+        let extensible = build_extensible_wav(SUBTYPE_PCM, &[1, 2]);
+        let mut source = WavFileSource::new(Cursor::new(extensible), 16000).unwrap();
+        assert_eq!(source.wait_until(4).unwrap(), &[1, 2]);
+        // KSDATAFORMAT_SUBTYPE_IEEE_FLOAT
+        let mut float = SUBTYPE_PCM;
+        float[0] = 0x03;
+        let extensible_float = build_extensible_wav(float, &[0, 0]);
+        assert!(matches!(
+            WavFileSource::new(Cursor::new(extensible_float), 16000),
+            Err(WavError::UnsupportedFormat(_))
+        ));
+        let mut huge_format = b"RIFF\x24\x00\x00\x00WAVEfmt ".to_vec();
+        huge_format.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            WavFileSource::new(Cursor::new(huge_format), 16000),
+            Err(WavError::UnsupportedFormat(_))
+        ));
+        let garbage = b"definitely not a wav file".to_vec();
+        assert!(matches!(
+            WavFileSource::new(Cursor::new(garbage), 16000),
+            Err(WavError::NotWave)
+        ));
+    }
+}